@@ -0,0 +1 @@
+if (ptr < memory || ptr >= memory + tape_size) out_of_range(ptr, "{LOCATION}");
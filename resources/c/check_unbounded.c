@@ -0,0 +1 @@
+check_pointer(ptr, "{LOCATION}");
@@ -0,0 +1 @@
+big_set(cell(ptr), 0); // [-]
@@ -1 +1,7 @@
+program_end:;
+#if !UNBOUNDED_CELLS
+  dump_tape_at_exit();
+  if (save_tape_path)
+    save_tape(save_tape_path, ptr);
+#endif
 }
@@ -1 +1 @@
-*ptr = get_character(); // ,
+*ptr = read_cell(*ptr); // ,
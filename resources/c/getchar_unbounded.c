@@ -0,0 +1 @@
+read_big(cell(ptr)); // ,
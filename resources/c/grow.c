@@ -0,0 +1 @@
+if ((size_t)(ptr - memory) + {CELLS} >= tape_size) ptr = grow_tape(ptr, {CELLS});
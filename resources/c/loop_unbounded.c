@@ -0,0 +1,3 @@
+while (!big_is_zero(cell(ptr))) {
+  { BODY }
+}
@@ -0,0 +1 @@
+big_mul_add(cell(ptr + {OFFSET}), cell(ptr), {FACTOR});
@@ -0,0 +1,3 @@
+if (!cell(ptr)->negative) {
+  { BODY }
+}
@@ -0,0 +1 @@
+putchar(big_low_byte(cell(ptr)));
@@ -1,16 +1,554 @@
+#define _GNU_SOURCE
+#include <signal.h>
+#include <stdarg.h>
+#include <stdint.h>
 #include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/mman.h>
+#include <ucontext.h>
+#include <unistd.h>
 
-char get_character() {
-  int character = getchar();
+// Sessions are a list of what `,` returned: 0 followed by the byte, or 1 for
+// end of input
+FILE *record_file = NULL;
+FILE *replay_file = NULL;
+
+// Tape snapshots for --load-tape and --save-tape: "BFT\0", a version byte and
+// the cell width in bits, then the number of cells, where cell 0 is on the
+// tape and the pointer as 64-bit integers, followed by the cells. Everything
+// is little endian.
+char *load_tape_path = NULL;
+char *save_tape_path = NULL;
+
+// Why this program can't use snapshots, or NULL
+#define SNAPSHOT_ERROR {SNAPSHOT_ERROR}
+
+#define LINE_INPUT {LINE_INPUT}
+
+#define BAKED_INPUT {BAKED_INPUT}
+
+#if BAKED_INPUT
+// Input compiled into the program with --bake-input, read instead of stdin
+const unsigned char baked_input[] = {{BAKED_INPUT_BYTES}};
+const size_t baked_input_size = {BAKED_INPUT_SIZE};
+size_t baked_input_position = 0;
+#endif
+
+int read_byte() {
+#if BAKED_INPUT
+  if (baked_input_position == baked_input_size)
+    return EOF;
+
+  return baked_input[baked_input_position++];
+#else
+  return getchar();
+#endif
+}
+
+int read_character() {
+  if (replay_file) {
+    if (fgetc(replay_file) != 0)
+      return EOF;
+
+    return fgetc(replay_file);
+  }
+
+  int character = read_byte();
   int tmp = character;
 
-  while (tmp != '\n')
-    tmp = getchar();
+  // Line mode throws away the rest of the line
+  if (LINE_INPUT)
+    while (tmp != '\n' && tmp != EOF)
+      tmp = read_byte();
 
   return character;
 }
 
-char memory[30000] = {0};
+int get_character() {
+  int character = read_character();
+
+  if (record_file) {
+    if (character == EOF) {
+      fputc(1, record_file);
+    } else {
+      fputc(0, record_file);
+      fputc(character, record_file);
+    }
+    fflush(record_file);
+  }
+
+  return character;
+}
+
+// What `,` stores at the end of input
+#define EOF_UNCHANGED 0
+#define EOF_ZERO 1
+#define EOF_MINUS_ONE 2
+#define EOF_MODE {EOF_MODE}
+
+FILE *open_session(char *path, char *mode) {
+  FILE *file = fopen(path, mode);
+
+  if (!file) {
+    fprintf(stderr, "Failed to open %s\n", path);
+    exit(1);
+  }
+
+  return file;
+}
+
+void parse_arguments(int argc, char **argv) {
+  for (int i = 1; i < argc; i++) {
+    // Programs used to take no options, other arguments are still ignored
+    if (strncmp(argv[i], "--", 2) != 0)
+      continue;
+
+    if (i + 1 < argc && strcmp(argv[i], "--record") == 0) {
+      record_file = open_session(argv[++i], "wb");
+    } else if (i + 1 < argc && strcmp(argv[i], "--replay") == 0) {
+      replay_file = open_session(argv[++i], "rb");
+    } else if (i + 1 < argc && strcmp(argv[i], "--load-tape") == 0) {
+      load_tape_path = argv[++i];
+    } else if (i + 1 < argc && strcmp(argv[i], "--save-tape") == 0) {
+      save_tape_path = argv[++i];
+    } else {
+      fprintf(stderr,
+              "Usage: %s [--record FILE] [--replay FILE] [--load-tape FILE] "
+              "[--save-tape FILE]\n",
+              argv[0]);
+      exit(1);
+    }
+  }
+
+  if (SNAPSHOT_ERROR && (load_tape_path || save_tape_path)) {
+    fprintf(stderr, "%s\n", SNAPSHOT_ERROR);
+    exit(1);
+  }
+}
+
+#define GROWABLE_TAPE {GROWABLE_TAPE}
+
+// Where cell 0 is on the tape, the middle of a bidirectional tape
+#define ORIGIN {ORIGIN}
+
+#define UNBOUNDED_CELLS {UNBOUNDED_CELLS}
+
+#if UNBOUNDED_CELLS
+{UNBOUNDED_RUNTIME}
+#else
+// Returns the new value of a cell that is currently `cell`
+{CELL} read_cell({CELL} cell) {
+  int character = get_character();
+
+  if (character != EOF)
+    return character;
+  else if (EOF_MODE == EOF_ZERO)
+    return 0;
+  else if (EOF_MODE == EOF_MINUS_ONE)
+    return -1;
+
+  return cell;
+}
+
+#define GUARD_PAGES {GUARD_PAGES}
+// Inaccessible memory on both sides of the tape, large enough that moving
+// the pointer off the tape lands in it
+#define GUARD_SIZE (1UL << 28)
+
+// Too large for a static array
+#define MAPPED_TAPE {MAPPED_TAPE}
+
+#if GUARD_PAGES || GROWABLE_TAPE || MAPPED_TAPE
+{CELL} *memory;
+#else
+{CELL} memory[{TAPE_SIZE}] = {0};
+#endif
+
+#if GROWABLE_TAPE
+// Number of cells on the tape, grows when the pointer moves past the end
+size_t tape_size = {TAPE_SIZE};
+#else
+const size_t tape_size = {TAPE_SIZE};
+#endif
+
+// Where the code of a command starts, to find the command that faulted or
+// was interrupted. The end of the program has no location.
+struct location {
+  void *address;
+  char *location;
+};
+
+struct location *locations;
+size_t location_count;
+char *mapping_start;
+char *mapping_end;
+
+// The location of the command that was running at `pc`, the last one that
+// starts before it, or NULL if it's unknown
+char *find_location(char *pc) {
+  struct location *found = NULL;
+
+  for (size_t i = 0; i < location_count; i++)
+    if ((char *)locations[i].address <= pc &&
+        (!found || locations[i].address > found->address))
+      found = &locations[i];
+
+  return found ? found->location : NULL;
+}
+
+void guard_page_fault(int number, siginfo_t *info, void *context) {
+  char *address = info->si_addr;
+
+  if (address < mapping_start || address >= mapping_end) {
+    // Not the tape, crash like without the handler
+    signal(SIGSEGV, SIG_DFL);
+    return;
+  }
+
+  long offset = address - (char *)memory;
+  long size = sizeof({CELL});
+  long index = offset >= 0 ? offset / size : -((size - 1 - offset) / size);
+
+  char *pc = (char *)((ucontext_t *)context)->uc_mcontext.gregs[REG_RIP];
+  char *location = find_location(pc);
+
+  fflush(stdout);
+  if (location)
+    fprintf(stderr, "pointer out of range at %s, pointer = %ld\n", location,
+            index);
+  else
+    fprintf(stderr, "pointer out of range, pointer = %ld\n", index);
+  _exit(1);
+}
+
+void allocate_tape() {
+#if GUARD_PAGES
+  size_t page = sysconf(_SC_PAGESIZE);
+  size_t tape = ({TAPE_SIZE} * sizeof({CELL}) + page - 1) / page * page;
+
+  mapping_start = mmap(NULL, tape + 2 * GUARD_SIZE, PROT_NONE,
+                       MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE, -1, 0);
+  if (mapping_start == MAP_FAILED ||
+      mprotect(mapping_start + GUARD_SIZE, tape, PROT_READ | PROT_WRITE)) {
+    perror("Failed to allocate the tape");
+    exit(1);
+  }
+  mapping_end = mapping_start + tape + 2 * GUARD_SIZE;
+
+  // Cell 0 is right after the lower guard. The tape is rounded up to whole
+  // pages, so only moving past those extra cells faults
+  memory = ({CELL} *)(mapping_start + GUARD_SIZE);
+
+  struct sigaction action = {0};
+  action.sa_sigaction = guard_page_fault;
+  action.sa_flags = SA_SIGINFO;
+  sigaction(SIGSEGV, &action, NULL);
+#elif GROWABLE_TAPE
+  memory = calloc(tape_size, sizeof({CELL}));
+  if (!memory) {
+    perror("Failed to allocate the tape");
+    exit(1);
+  }
+#elif MAPPED_TAPE
+  // Pages of the tape only take up memory once they're written
+  memory = mmap(NULL, tape_size * sizeof({CELL}), PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE, -1, 0);
+  if (memory == MAP_FAILED) {
+    perror("Failed to allocate the tape");
+    exit(1);
+  }
+#endif
+}
+
+#if GROWABLE_TAPE
+// Grows the tape until the cell `cells` right of `pointer` is on it and
+// returns where `pointer` is on the new tape
+{CELL} *grow_tape({CELL} *pointer, size_t cells) {
+  if (pointer < memory) {
+    fflush(stdout);
+    fprintf(stderr, "pointer out of range, pointer = %td\n", pointer - memory);
+    exit(1);
+  }
+
+  size_t index = pointer - memory;
+  size_t size = tape_size * 2;
+  if (size <= index + cells)
+    size = index + cells + 1;
+
+  memory = realloc(memory, size * sizeof({CELL}));
+  if (!memory) {
+    perror("Failed to grow the tape");
+    exit(1);
+  }
+  memset(memory + tape_size, 0, (size - tape_size) * sizeof({CELL}));
+  tape_size = size;
+
+  return memory + index;
+}
+#endif
+
+void out_of_range({CELL} *pointer, char *location) {
+  fflush(stdout);
+  fprintf(stderr, "pointer out of range at %s, pointer = %td\n", location,
+          pointer - memory - ORIGIN);
+  exit(1);
+}
+
+#define SNAPSHOT_VERSION 1
+
+void snapshot_error(char *path, char *format, ...) {
+  va_list arguments;
+
+  va_start(arguments, format);
+  fprintf(stderr, "%s: ", path);
+  vfprintf(stderr, format, arguments);
+  fputc('\n', stderr);
+  va_end(arguments);
+  exit(1);
+}
+
+void read_snapshot(FILE *file, void *data, size_t size, char *path) {
+  if (fread(data, 1, size, file) != size)
+    snapshot_error(path, "unexpected end of snapshot");
+}
+
+// Replaces the tape with a snapshot and returns the pointer from it
+{CELL} *load_tape(char *path) {
+  FILE *file = open_session(path, "rb");
+  char magic[4];
+  uint8_t version, bits;
+  uint64_t size, origin;
+  int64_t pointer;
+
+  read_snapshot(file, magic, sizeof(magic), path);
+  if (memcmp(magic, "BFT", sizeof(magic)) != 0)
+    snapshot_error(path, "not a tape snapshot");
+  read_snapshot(file, &version, 1, path);
+  if (version != SNAPSHOT_VERSION)
+    snapshot_error(path, "unsupported snapshot version %d", version);
+  read_snapshot(file, &bits, 1, path);
+  read_snapshot(file, &size, 8, path);
+  read_snapshot(file, &origin, 8, path);
+  read_snapshot(file, &pointer, 8, path);
+
+  if (bits != sizeof({CELL}) * 8)
+    snapshot_error(path, "snapshot of %d-bit cells, compile with --cell-bits %d",
+                   bits, bits);
+  if (!GROWABLE_TAPE && size != tape_size)
+    snapshot_error(path, "snapshot of %lu cells, compile with --tape-size %lu",
+                   size, size);
+  if (origin != ORIGIN)
+    snapshot_error(path,
+                   "snapshot with cell 0 at %lu, compile with the same --tape",
+                   origin);
+
+  int64_t index = pointer + ORIGIN;
+  if (index < 0 || (!GROWABLE_TAPE && (uint64_t)index >= size))
+    snapshot_error(path, "pointer %ld is off the tape", pointer);
+
+#if GROWABLE_TAPE
+  // A growable tape grows to fit the snapshot and the pointer, the cells past
+  // a shorter snapshot stay 0
+  uint64_t cells = size > (uint64_t)index ? size : index + 1;
+  if (cells > tape_size)
+    memory = grow_tape(memory, cells - 1);
+#endif
+
+  if (fread(memory, sizeof({CELL}), size, file) != size)
+    snapshot_error(path, "unexpected end of snapshot");
+  if (fgetc(file) != EOF)
+    snapshot_error(path, "unexpected data after the cells");
+  fclose(file);
+
+  return memory + index;
+}
+
+void save_tape(char *path, {CELL} *pointer) {
+  FILE *file = open_session(path, "wb");
+  uint8_t header[] = {SNAPSHOT_VERSION, sizeof({CELL}) * 8};
+  uint64_t size = tape_size;
+  uint64_t origin = ORIGIN;
+  int64_t index = pointer - memory - ORIGIN;
+
+  fwrite("BFT", 1, 4, file);
+  fwrite(header, 1, sizeof(header), file);
+  fwrite(&size, 8, 1, file);
+  fwrite(&origin, 8, 1, file);
+  fwrite(&index, 8, 1, file);
+  fwrite(memory, sizeof({CELL}), tape_size, file);
+
+  if (ferror(file) | fclose(file)) {
+    perror("Failed to save the tape");
+    exit(1);
+  }
+}
+
+// What `+` and `-` do when a cell overflows
+#define OVERFLOW_WRAP 0
+#define OVERFLOW_SATURATE 1
+#define OVERFLOW_TRAP 2
+#define CELL_OVERFLOW {CELL_OVERFLOW}
+
+// Adds `amount` to a cell that doesn't wrap, {ARITHMETIC_CELL} is signed if
+// cells are
+void add_cell({CELL} *cell, long long amount, char *location) {
+  {ARITHMETIC_CELL} result;
+
+  if (!__builtin_add_overflow(({ARITHMETIC_CELL})*cell, amount, &result)) {
+    *cell = result;
+    return;
+  }
+
+  if (CELL_OVERFLOW == OVERFLOW_TRAP) {
+    fflush(stdout);
+    fprintf(stderr, "cell overflow at %s, pointer = %td\n", location,
+            cell - memory - ORIGIN);
+    exit(1);
+  }
+
+  *cell = amount < 0 ? {CELL_MIN} : {CELL_MAX};
+}
+
+#define DUMP_TAPE {DUMP_TAPE}
+// Cells on each side of the pointer that a dump shows
+#define DUMP_RADIUS 16
+
+#if DUMP_TAPE
+// The signal handler reads the pointer, so it has to stay in memory
+#define DUMPED volatile
+#else
+#define DUMPED
+#endif
+
+// The pointer of main while the tape is dumped on exit
+{CELL} *volatile *dumped_pointer = NULL;
+
+// The dump is written with write from this buffer instead of with stdio,
+// which the signal handler can't use
+char dump_buffer[4096];
+size_t dump_size = 0;
+
+void dump_flush() {
+  size_t written = 0;
+
+  while (written < dump_size) {
+    ssize_t result = write(2, dump_buffer + written, dump_size - written);
+    if (result <= 0)
+      break;
+    written += result;
+  }
+  dump_size = 0;
+}
+
+void dump_string(char *string) {
+  for (; *string; string++) {
+    if (dump_size == sizeof(dump_buffer))
+      dump_flush();
+    dump_buffer[dump_size++] = *string;
+  }
+}
+
+// `value` in `base` with at least `width` digits
+void dump_number(unsigned long long value, int negative, int base, int width) {
+  char digits[24];
+  int i = sizeof(digits) - 1;
+
+  digits[i] = 0;
+  do {
+    digits[--i] = "0123456789abcdef"[value % base];
+    value /= base;
+  } while (value || (int)sizeof(digits) - 1 - i < width);
+  if (negative)
+    digits[--i] = '-';
+
+  dump_string(digits + i);
+}
+
+void dump_signed(long long value) {
+  dump_number(value < 0 ? -(unsigned long long)value : value, value < 0, 10,
+              1);
+}
+
+// Prints the pointer and the cells around it that aren't 0
+void dump_tape(char *reason, char *location) {
+  long index = *dumped_pointer - memory;
+
+  dump_string("tape at ");
+  dump_string(reason);
+  dump_string(": pointer = ");
+  dump_signed(index - ORIGIN);
+  if (location) {
+    dump_string(", at ");
+    dump_string(location);
+  }
+  dump_string("\n");
+
+  long first = index - DUMP_RADIUS < 0 ? 0 : index - DUMP_RADIUS;
+  long last = index + DUMP_RADIUS < (long)tape_size ? index + DUMP_RADIUS
+                                                     : (long)tape_size - 1;
+
+  for (long i = first; i <= last; i++) {
+    if (!memory[i] && i != index)
+      continue;
+
+    dump_string("  cell ");
+    dump_signed(i - ORIGIN);
+    dump_string(" = 0x");
+    dump_number(memory[i], 0, 16, sizeof({CELL}) * 2);
+    dump_string(" ");
+    if (({ARITHMETIC_CELL})-1 < 0)
+      dump_signed(({ARITHMETIC_CELL})memory[i]);
+    else
+      dump_number(memory[i], 0, 10, 1);
+    dump_string(i == index ? " <- pointer\n" : "\n");
+  }
+
+  dump_flush();
+}
+
+void dump_tape_at_exit() {
+  if (!dumped_pointer)
+    return;
+
+  fflush(stdout);
+  dump_tape("exit", NULL);
+  // Once main returns the pointer is gone
+  dumped_pointer = NULL;
+}
+
+void dump_tape_on_signal(int number, siginfo_t *info, void *context) {
+  char *pc = (char *)((ucontext_t *)context)->uc_mcontext.gregs[REG_RIP];
+
+  dump_tape(number == SIGINT ? "SIGINT" : "SIGTERM", find_location(pc));
+
+  // Die from the signal like without the handler
+  signal(number, SIG_DFL);
+  raise(number);
+}
+
+void dump_tape_on_exit({CELL} *volatile *pointer) {
+  dumped_pointer = pointer;
+  atexit(dump_tape_at_exit);
+
+  struct sigaction action = {0};
+  action.sa_sigaction = dump_tape_on_signal;
+  action.sa_flags = SA_SIGINFO;
+  sigaction(SIGINT, &action, NULL);
+  sigaction(SIGTERM, &action, NULL);
+}
+#endif
 
-int main() {
-  char *ptr = memory;
+int main(int argc, char **argv) {
+  parse_arguments(argc, argv);
+  allocate_tape();
+{LOCATIONS}
+#if UNBOUNDED_CELLS
+  long ptr = 0;
+#else
+  {CELL} *DUMPED ptr = memory + ORIGIN;
+  if (load_tape_path)
+    ptr = load_tape(load_tape_path);
+  if (DUMP_TAPE)
+    dump_tape_on_exit(&ptr);
+#endif
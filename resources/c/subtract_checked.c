@@ -0,0 +1 @@
+add_cell(ptr, -{TIMES}, "{LOCATION}"); // -
@@ -0,0 +1 @@
+big_add(cell(ptr), -{TIMES}); // -
@@ -0,0 +1,213 @@
+// Arbitrary-precision cells: a sign and a magnitude in base 2^32, least
+// significant limb first. All zero bytes is the number 0, so cells on a fresh
+// page of the tape don't need to be initialized.
+typedef struct {
+  int negative;
+  // Limbs in use, without leading zeros
+  int size;
+  int capacity;
+  uint32_t *limbs;
+} big;
+
+void big_reserve(big *number, int size) {
+  if (number->capacity >= size)
+    return;
+
+  int capacity = number->capacity ? number->capacity : 2;
+  while (capacity < size)
+    capacity *= 2;
+
+  number->limbs = realloc(number->limbs, capacity * sizeof(uint32_t));
+  if (!number->limbs) {
+    perror("Failed to grow a cell");
+    exit(1);
+  }
+  number->capacity = capacity;
+}
+
+void big_trim(big *number) {
+  while (number->size && !number->limbs[number->size - 1])
+    number->size--;
+
+  if (!number->size)
+    number->negative = 0;
+}
+
+int big_compare(big *number, uint32_t *limbs, int size) {
+  if (number->size != size)
+    return number->size < size ? -1 : 1;
+
+  for (int i = size - 1; i >= 0; i--)
+    if (number->limbs[i] != limbs[i])
+      return number->limbs[i] < limbs[i] ? -1 : 1;
+
+  return 0;
+}
+
+// Adds the number with `limbs` as its magnitude
+void big_add_magnitude(big *number, int negative, uint32_t *limbs, int size) {
+  if (!number->size || number->negative == negative) {
+    int result = (number->size > size ? number->size : size) + 1;
+    big_reserve(number, result);
+
+    uint64_t carry = 0;
+    for (int i = 0; i < result; i++) {
+      carry += (i < number->size ? number->limbs[i] : 0);
+      carry += (i < size ? limbs[i] : 0);
+      number->limbs[i] = carry;
+      carry >>= 32;
+    }
+
+    number->negative = negative;
+    number->size = result;
+  } else if (big_compare(number, limbs, size) >= 0) {
+    int64_t borrow = 0;
+    for (int i = 0; i < number->size; i++) {
+      int64_t difference =
+          (int64_t)number->limbs[i] - (i < size ? limbs[i] : 0) - borrow;
+      borrow = difference < 0;
+      number->limbs[i] = difference + (borrow << 32);
+    }
+  } else {
+    // The result is `limbs` minus the number, with the other sign
+    big_reserve(number, size);
+
+    int64_t borrow = 0;
+    for (int i = 0; i < size; i++) {
+      int64_t difference = (int64_t)limbs[i] -
+                           (i < number->size ? number->limbs[i] : 0) - borrow;
+      borrow = difference < 0;
+      number->limbs[i] = difference + (borrow << 32);
+    }
+
+    number->negative = negative;
+    number->size = size;
+  }
+
+  big_trim(number);
+}
+
+void big_add(big *number, long long amount) {
+  unsigned long long magnitude =
+      amount < 0 ? -(unsigned long long)amount : amount;
+  uint32_t limbs[2] = {magnitude, magnitude >> 32};
+
+  big_add_magnitude(number, amount < 0, limbs, limbs[1] ? 2 : limbs[0] ? 1 : 0);
+}
+
+void big_set(big *number, long long value) {
+  number->size = 0;
+  number->negative = 0;
+  big_add(number, value);
+}
+
+// number += other * factor, with `other` a different cell
+void big_mul_add(big *number, big *other, long long factor) {
+  static big product;
+  unsigned long long magnitude =
+      factor < 0 ? -(unsigned long long)factor : factor;
+
+  big_reserve(&product, other->size + 2);
+
+  unsigned __int128 carry = 0;
+  for (int i = 0; i < other->size; i++) {
+    carry += (unsigned __int128)other->limbs[i] * magnitude;
+    product.limbs[i] = carry;
+    carry >>= 32;
+  }
+  product.limbs[other->size] = carry;
+  product.limbs[other->size + 1] = carry >> 32;
+  product.size = other->size + 2;
+  big_trim(&product);
+
+  big_add_magnitude(number, other->negative != (factor < 0), product.limbs,
+                    product.size);
+}
+
+int big_is_zero(big *number) { return !number->size; }
+
+// The low byte of the two's complement, what `.` writes
+int big_low_byte(big *number) {
+  uint8_t byte = number->size ? number->limbs[0] : 0;
+
+  return number->negative ? (uint8_t)-byte : byte;
+}
+
+void read_big(big *cell) {
+  int character = get_character();
+
+  if (character != EOF)
+    big_set(cell, character);
+  else if (EOF_MODE == EOF_ZERO)
+    big_set(cell, 0);
+  else if (EOF_MODE == EOF_MINUS_ONE)
+    big_set(cell, -1);
+}
+
+// The tape is sparse: it's split into pages of cells that are only allocated
+// once one of their cells is used, and `ptr` is the index of a cell instead
+// of its address
+#define PAGE_CELLS 4096
+
+big **pages;
+size_t page_count;
+
+void allocate_tape() {
+  page_count = ({TAPE_SIZE} + PAGE_CELLS - 1) / PAGE_CELLS;
+  pages = calloc(page_count, sizeof(big *));
+  if (!pages) {
+    perror("Failed to allocate the tape");
+    exit(1);
+  }
+}
+
+void out_of_range(long pointer, char *location) {
+  fflush(stdout);
+  if (location)
+    fprintf(stderr, "pointer out of range at %s, pointer = %ld\n", location,
+            pointer);
+  else
+    fprintf(stderr, "pointer out of range, pointer = %ld\n", pointer);
+  exit(1);
+}
+
+// Only a growable tape has cells past --tape-size
+int on_tape(long pointer) {
+  long index = pointer + ORIGIN;
+
+  return index >= 0 && (GROWABLE_TAPE || index < {TAPE_SIZE});
+}
+
+void check_pointer(long pointer, char *location) {
+  if (!on_tape(pointer))
+    out_of_range(pointer, location);
+}
+
+big *cell(long pointer) {
+  check_pointer(pointer, NULL);
+
+  size_t index = pointer + ORIGIN;
+  size_t page = index / PAGE_CELLS;
+
+  if (page >= page_count) {
+    size_t count = page_count * 2 > page ? page_count * 2 : page + 1;
+
+    pages = realloc(pages, count * sizeof(big *));
+    if (!pages) {
+      perror("Failed to grow the tape");
+      exit(1);
+    }
+    memset(pages + page_count, 0, (count - page_count) * sizeof(big *));
+    page_count = count;
+  }
+
+  if (!pages[page]) {
+    pages[page] = calloc(PAGE_CELLS, sizeof(big));
+    if (!pages[page]) {
+      perror("Failed to allocate the tape");
+      exit(1);
+    }
+  }
+
+  return &pages[page][index % PAGE_CELLS];
+}
\ No newline at end of file
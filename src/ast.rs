@@ -1,12 +1,24 @@
+use crate::span::Span;
+
 // usize arguments refer to how many in a row
 #[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum AST {
     Root(Vec<AST>),
-    Right(usize),
-    Left(usize),
-    Add(usize),
-    Subtract(usize),
-    PrintChar,
-    GetChar,
-    Loop(Vec<AST>),
+    Right(usize, Span),
+    Left(usize, Span),
+    Add(usize, Span),
+    Subtract(usize, Span),
+    PrintChar(Span),
+    GetChar(Span),
+    Loop(Vec<AST>, Span),
+    // Only inserted for compiled backends, aborts if the pointer is off the tape
+    BoundsCheck(Span),
+    // Only inserted for growable tapes, grows the tape until the cell this many
+    // cells right of the pointer is on it
+    GrowTape(usize),
+    // Only inserted for unbounded cells in front of a loop that counts down,
+    // adds the current cell times the factor to the cell at every offset and
+    // then clears it, unless the current cell is negative
+    Multiply(Vec<(i64, i64)>, Span),
 }
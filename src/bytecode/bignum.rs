@@ -0,0 +1,279 @@
+// Arbitrary-precision integers for `--cell-bits unbounded`. Only what cells
+// need: adding, multiplying by the factor of a `MulAdd` and printing them.
+
+use std::{cmp::Ordering, fmt};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BigInt {
+    negative: bool,
+    // Magnitude in base 2^32, least significant limb first and without
+    // leading zeros, so 0 has no limbs
+    limbs: Vec<u32>,
+}
+
+impl From<i64> for BigInt {
+    fn from(value: i64) -> Self {
+        let mut number = BigInt::default();
+        number.add(value);
+        number
+    }
+}
+
+impl BigInt {
+    pub fn is_zero(&self) -> bool {
+        self.limbs.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    // The low byte of the two's complement, what `.` writes
+    pub fn low_byte(&self) -> u8 {
+        let byte = self.limbs.first().map_or(0, |limb| *limb as u8);
+
+        if self.negative {
+            byte.wrapping_neg()
+        } else {
+            byte
+        }
+    }
+
+    // The value if it fits in an i64
+    pub fn to_i64(&self) -> Option<i64> {
+        let magnitude = match self.limbs[..] {
+            [] => 0,
+            [low] => low as i128,
+            [low, high] => (high as i128) << 32 | low as i128,
+            _ => return None,
+        };
+
+        i64::try_from(if self.negative { -magnitude } else { magnitude }).ok()
+    }
+
+    pub fn add(&mut self, amount: i64) {
+        let magnitude = amount.unsigned_abs();
+        let limbs = [magnitude as u32, (magnitude >> 32) as u32];
+        let size = limbs
+            .iter()
+            .rposition(|limb| *limb != 0)
+            .map_or(0, |i| i + 1);
+
+        self.add_magnitude(amount < 0, &limbs[..size]);
+    }
+
+    // self += other * factor
+    pub fn mul_add(&mut self, other: &BigInt, factor: i64) {
+        let factor_magnitude = factor.unsigned_abs() as u128;
+        let mut product = Vec::with_capacity(other.limbs.len() + 2);
+        let mut carry = 0u128;
+
+        for limb in &other.limbs {
+            carry += *limb as u128 * factor_magnitude;
+            product.push(carry as u32);
+            carry >>= 32;
+        }
+        product.extend([carry as u32, (carry >> 32) as u32]);
+        trim(&mut product);
+
+        self.add_magnitude(other.negative != (factor < 0), &product);
+    }
+
+    fn add_magnitude(&mut self, negative: bool, limbs: &[u32]) {
+        if self.is_zero() || self.negative == negative {
+            self.negative = negative;
+            add_limbs(&mut self.limbs, limbs);
+        } else if compare(&self.limbs, limbs) != Ordering::Less {
+            subtract_limbs(&mut self.limbs, limbs);
+        } else {
+            let mut difference = limbs.to_vec();
+            subtract_limbs(&mut difference, &self.limbs);
+            self.limbs = difference;
+            self.negative = negative;
+        }
+
+        trim(&mut self.limbs);
+        if self.is_zero() {
+            self.negative = false;
+        }
+    }
+}
+
+fn trim(limbs: &mut Vec<u32>) {
+    while limbs.last() == Some(&0) {
+        limbs.pop();
+    }
+}
+
+fn compare(a: &[u32], b: &[u32]) -> Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_limbs(a: &mut Vec<u32>, b: &[u32]) {
+    if a.len() < b.len() {
+        a.resize(b.len(), 0);
+    }
+
+    let mut carry = 0u64;
+    for (i, limb) in a.iter_mut().enumerate() {
+        carry += *limb as u64 + b.get(i).copied().unwrap_or(0) as u64;
+        *limb = carry as u32;
+        carry >>= 32;
+    }
+
+    if carry != 0 {
+        a.push(carry as u32);
+    }
+}
+
+// a -= b, where a is at least b
+fn subtract_limbs(a: &mut [u32], b: &[u32]) {
+    let mut borrow = 0i64;
+
+    for (i, limb) in a.iter_mut().enumerate() {
+        let difference = *limb as i64 - b.get(i).copied().unwrap_or(0) as i64 - borrow;
+        borrow = (difference < 0) as i64;
+        *limb = difference.rem_euclid(1 << 32) as u32;
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Digits in groups of 9, least significant group first
+        let mut groups = vec![];
+        let mut limbs = self.limbs.clone();
+
+        while !limbs.is_empty() {
+            let mut remainder = 0u64;
+            for limb in limbs.iter_mut().rev() {
+                let value = remainder << 32 | *limb as u64;
+                *limb = (value / 1_000_000_000) as u32;
+                remainder = value % 1_000_000_000;
+            }
+
+            groups.push(remainder);
+            trim(&mut limbs);
+        }
+
+        if self.negative {
+            write!(f, "-")?;
+        }
+
+        match groups.split_last() {
+            Some((first, rest)) => {
+                write!(f, "{first}")?;
+                for group in rest.iter().rev() {
+                    write!(f, "{group:09}")?;
+                }
+                Ok(())
+            }
+            None => write!(f, "0"),
+        }
+    }
+}
+
+impl fmt::LowerHex for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.negative {
+            write!(f, "-")?;
+        }
+
+        match self.limbs.split_last() {
+            Some((first, rest)) => {
+                write!(f, "{first:02x}")?;
+                for limb in rest.iter().rev() {
+                    write!(f, "{limb:08x}")?;
+                }
+                Ok(())
+            }
+            None => write!(f, "00"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_values() {
+        for value in [0, 1, -1, 255, -256, 1 << 32, i64::MAX, i64::MIN] {
+            let number = BigInt::from(value);
+
+            assert_eq!(number.to_i64(), Some(value));
+            assert_eq!(number.to_string(), value.to_string());
+        }
+    }
+
+    #[test]
+    fn add_across_zero() {
+        let mut number = BigInt::from(3);
+        number.add(-5);
+        assert_eq!(number.to_i64(), Some(-2));
+
+        number.add(2);
+        assert!(number.is_zero());
+        // Zero is never negative
+        assert_eq!(number, BigInt::default());
+        assert_eq!(number.to_string(), "0");
+    }
+
+    #[test]
+    fn past_64_bits() {
+        let mut number = BigInt::from(i64::MAX);
+        number.add(i64::MAX);
+        number.add(2);
+
+        assert_eq!(number.to_i64(), None);
+        assert_eq!(number.to_string(), "18446744073709551616");
+        assert_eq!(format!("{number:x}"), "010000000000000000");
+
+        number.add(-1);
+        assert_eq!(number.to_string(), "18446744073709551615");
+
+        let mut negative = BigInt::from(i64::MIN);
+        negative.add(-1);
+        assert_eq!(negative.to_i64(), None);
+        assert_eq!(negative.to_string(), "-9223372036854775809");
+    }
+
+    #[test]
+    fn mul_add() {
+        let mut number = BigInt::from(10);
+        number.mul_add(&BigInt::from(-7), 3);
+        assert_eq!(number.to_i64(), Some(-11));
+
+        number.mul_add(&BigInt::from(-7), -3);
+        assert_eq!(number.to_i64(), Some(10));
+
+        // 10^18 * 10^18 needs four limbs, and groups of digits keep their
+        // leading zeros
+        let mut square = BigInt::default();
+        square.mul_add(
+            &BigInt::from(1_000_000_000_000_000_000),
+            1_000_000_000_000_000_000,
+        );
+        assert_eq!(square.to_string(), format!("1{}", "0".repeat(36)));
+
+        square.mul_add(&BigInt::from(0), i64::MIN);
+        assert_eq!(square.to_string(), format!("1{}", "0".repeat(36)));
+    }
+
+    #[test]
+    fn low_byte() {
+        assert_eq!(BigInt::from(0).low_byte(), 0);
+        assert_eq!(BigInt::from(65).low_byte(), 65);
+        assert_eq!(BigInt::from(-1).low_byte(), 255);
+        assert_eq!(BigInt::from(-256).low_byte(), 0);
+        assert_eq!(BigInt::from(0x1_0000_0141).low_byte(), 0x41);
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(format!("{:x}", BigInt::from(0)), "00");
+        assert_eq!(format!("{:x}", BigInt::from(10)), "0a");
+        assert_eq!(format!("{:x}", BigInt::from(-255)), "-ff");
+    }
+}
@@ -0,0 +1,344 @@
+use std::collections::BTreeMap;
+
+use super::{cell_mask, cell_range, cell_value, Cell, Op, Program};
+use crate::{
+    ast::AST,
+    cli::{CellOverflow, UNBOUNDED_CELL_BITS},
+    codegen::CodegenOptions,
+    span::Span,
+};
+
+pub fn compile(ast: &AST, options: &CodegenOptions) -> Program {
+    let mut compiler = Compiler {
+        program: Program {
+            cell_bits: options.cell_bits,
+            cell_overflow: options.cell_overflow,
+            cell_signed: options.cell_signed,
+            ..Program::default()
+        },
+        offset: 0,
+        offset_span: None,
+        optimized: options.optimized,
+        mask: cell_mask(options.cell_bits),
+        // Unbounded cells never overflow, their values wrap to an i64
+        wraps: options.cell_overflow == CellOverflow::Wrap
+            || options.cell_bits == UNBOUNDED_CELL_BITS,
+    };
+
+    match ast {
+        AST::Root(statments) => compiler.compile_statments(statments),
+        _ => panic!("Expected AST::Root"),
+    }
+    compiler.flush_offset();
+
+    compiler.program
+}
+
+struct Compiler {
+    program: Program,
+    // Pointer movement that hasn't been emitted yet, used for offset addressing
+    offset: i32,
+    offset_span: Option<Span>,
+    optimized: bool,
+    mask: Cell,
+    // Otherwise `+` and `-` saturate or trap, so runs in opposite directions
+    // can't be folded and loops that count past the limits aren't simple
+    wraps: bool,
+}
+
+impl Compiler {
+    fn emit(&mut self, op: Op, span: Span) {
+        self.program.ops.push(op);
+        self.program.spans.push(span);
+    }
+
+    fn compile_statments(&mut self, statments: &[AST]) {
+        for statment in statments {
+            self.compile_statment(statment);
+        }
+    }
+
+    fn compile_statment(&mut self, statment: &AST) {
+        match statment {
+            AST::Right(times, span) => self.move_pointer(*times as i32, *span),
+            AST::Left(times, span) => self.move_pointer(-(*times as i32), *span),
+            AST::Add(times, span) => self.add(self.amount(*times as i64), *span),
+            AST::Subtract(times, span) => self.add(self.amount(-(*times as i64)), *span),
+            AST::PrintChar(span) => self.emit(
+                Op::Output {
+                    offset: self.offset,
+                },
+                *span,
+            ),
+            AST::GetChar(span) => self.emit(
+                Op::Input {
+                    offset: self.offset,
+                },
+                *span,
+            ),
+            AST::Loop(statments, span) => self.compile_loop(statments, *span),
+            _ => unreachable!(),
+        }
+    }
+
+    fn wrap(&self, value: Cell) -> Cell {
+        value & self.mask
+    }
+
+    // The value of an `Add` of `amount`
+    fn amount(&self, amount: i64) -> Cell {
+        if self.wraps {
+            self.wrap(amount as Cell)
+        } else {
+            amount as Cell
+        }
+    }
+
+    // What adding `amount` to the value of the last op gives, if it's the same
+    // as running them one after the other
+    fn fold(&self, last: &Op, amount: Cell) -> Option<Cell> {
+        if self.wraps {
+            return match *last {
+                Op::Add { value, .. } | Op::Set { value, .. } => {
+                    Some(value.wrapping_add(amount) & self.mask)
+                }
+                _ => None,
+            };
+        }
+
+        let program = &self.program;
+        match *last {
+            // Going the same way overflows at the same point
+            Op::Add { value, .. } if (value as i64 >= 0) == (amount as i64 >= 0) => {
+                Some((value as i64).saturating_add(amount as i64) as Cell)
+            }
+            Op::Set { value, .. } => {
+                let value = cell_value(value, program.cell_bits, program.cell_signed);
+                let sum = value + amount as i64 as i128;
+                let (min, max) = cell_range(program.cell_bits, program.cell_signed);
+
+                (min..=max)
+                    .contains(&sum)
+                    .then_some(sum as Cell & self.mask)
+            }
+            _ => None,
+        }
+    }
+
+    fn move_pointer(&mut self, amount: i32, span: Span) {
+        self.offset += amount;
+        self.offset_span = Some(self.offset_span.map_or(span, |s| s.to(span)));
+
+        if !self.optimized {
+            self.flush_offset();
+        }
+    }
+
+    fn flush_offset(&mut self) {
+        if let Some(span) = self.offset_span.take() {
+            if self.offset != 0 {
+                self.emit(Op::Move(self.offset), span);
+                self.offset = 0;
+            }
+        }
+    }
+
+    fn add(&mut self, amount: Cell, span: Span) {
+        let offset = self.offset;
+
+        if self.optimized {
+            let folded = match self.program.ops.last() {
+                Some(last @ (Op::Add { offset: o, .. } | Op::Set { offset: o, .. }))
+                    if *o == offset =>
+                {
+                    self.fold(last, amount)
+                }
+                _ => None,
+            };
+
+            if let Some(folded) = folded {
+                if let Some(Op::Add { value, .. } | Op::Set { value, .. }) =
+                    self.program.ops.last_mut()
+                {
+                    *value = folded;
+                }
+
+                let last = self.program.spans.last_mut().unwrap();
+                *last = last.to(span);
+                return;
+            }
+        }
+
+        self.emit(
+            Op::Add {
+                offset,
+                value: amount,
+            },
+            span,
+        );
+    }
+
+    fn compile_loop(&mut self, statments: &[AST], span: Span) {
+        if self.optimized && self.compile_simple_loop(statments, span) {
+            return;
+        }
+
+        self.flush_offset();
+        let start = self.program.ops.len();
+        self.emit(Op::JumpIfZero(0), Span::at(span.start));
+
+        self.compile_statments(statments);
+        self.flush_offset();
+
+        let end = self.program.ops.len();
+        self.emit(Op::JumpIfNonZero(start as u32 + 1), Span::at(span.end));
+        self.program.ops[start] = Op::JumpIfZero(end as u32 + 1);
+    }
+
+    // Turns clear, scan and multiply loops into a handful of ops without jumps
+    fn compile_simple_loop(&mut self, statments: &[AST], span: Span) -> bool {
+        // A scan leaves the tape where compiled programs check the cell again
+        // after every move, at the `]`
+        match statments {
+            [AST::Right(times, _)] => {
+                self.flush_offset();
+                self.emit(Op::Scan(*times as i32), Span::at(span.end));
+                return true;
+            }
+            [AST::Left(times, _)] => {
+                self.flush_offset();
+                self.emit(Op::Scan(-(*times as i32)), Span::at(span.end));
+                return true;
+            }
+            _ => {}
+        }
+
+        // The loop is still needed for unbounded cells that are negative
+        if self.program.cell_bits == UNBOUNDED_CELL_BITS {
+            self.compile_multiplication(statments, span);
+            return false;
+        }
+
+        // Counting down to 0 from a positive value never overflows, but from
+        // a negative one or counting up does, so only `[-]` on unsigned cells
+        // is still a clear
+        if !self.wraps {
+            if !matches!(statments, [AST::Subtract(1, _)]) || self.program.cell_signed {
+                return false;
+            }
+
+            self.emit(
+                Op::Set {
+                    offset: self.offset,
+                    value: 0,
+                },
+                span,
+            );
+            return true;
+        }
+
+        let mut offset = 0;
+        let mut deltas: BTreeMap<i32, Cell> = BTreeMap::new();
+
+        for statment in statments {
+            match statment {
+                AST::Right(times, _) => offset += *times as i32,
+                AST::Left(times, _) => offset -= *times as i32,
+                AST::Add(times, _) => {
+                    let delta = deltas.entry(offset).or_default();
+                    *delta = self.wrap(delta.wrapping_add(*times as Cell));
+                }
+                AST::Subtract(times, _) => {
+                    let delta = deltas.entry(offset).or_default();
+                    *delta = self.wrap(delta.wrapping_sub(*times as Cell));
+                }
+                _ => return false,
+            }
+        }
+
+        if offset != 0 {
+            return false;
+        }
+
+        let counter = deltas.remove(&0).unwrap_or(0);
+        deltas.retain(|_, delta| *delta != 0);
+
+        // The counter has to count down by one, or up by one until it wraps
+        if deltas.is_empty() && (counter == 1 || counter == self.mask) {
+            self.emit(
+                Op::Set {
+                    offset: self.offset,
+                    value: 0,
+                },
+                span,
+            );
+            return true;
+        }
+
+        if counter != self.mask {
+            return false;
+        }
+
+        self.flush_offset();
+        for (offset, factor) in deltas {
+            self.emit(Op::MulAdd { offset, factor }, span);
+        }
+        self.emit(
+            Op::Set {
+                offset: 0,
+                value: 0,
+            },
+            span,
+        );
+
+        true
+    }
+
+    // Unbounded cells never wrap, so a loop that counts the current cell down
+    // by one only ends if it isn't negative. It's multiplied out when it
+    // isn't, like the C backend does, and the loop is kept after that for
+    // when it is.
+    fn compile_multiplication(&mut self, statments: &[AST], span: Span) {
+        let mut offset = 0;
+        let mut deltas: BTreeMap<i32, i64> = BTreeMap::new();
+
+        for statment in statments {
+            match statment {
+                AST::Right(times, _) => offset += *times as i32,
+                AST::Left(times, _) => offset -= *times as i32,
+                AST::Add(times, _) => *deltas.entry(offset).or_default() += *times as i64,
+                AST::Subtract(times, _) => *deltas.entry(offset).or_default() -= *times as i64,
+                _ => return,
+            }
+        }
+
+        if offset != 0 || deltas.remove(&0) != Some(-1) {
+            return;
+        }
+
+        self.flush_offset();
+        let guard = self.program.ops.len();
+        self.emit(Op::JumpIfNegative(0), span);
+
+        for (offset, factor) in deltas {
+            if factor != 0 {
+                self.emit(
+                    Op::MulAdd {
+                        offset,
+                        factor: factor as Cell,
+                    },
+                    span,
+                );
+            }
+        }
+        self.emit(
+            Op::Set {
+                offset: 0,
+                value: 0,
+            },
+            span,
+        );
+
+        self.program.ops[guard] = Op::JumpIfNegative(self.program.ops.len() as u32);
+    }
+}
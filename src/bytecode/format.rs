@@ -0,0 +1,510 @@
+// `.bfc` files: a magic number, version byte, cell width in bits (0 when
+// unbounded), overflow mode and whether cells are signed followed by the
+// number of ops, the ops themselves and the source span of every op. Every op is an opcode byte
+// followed by its operands as LEB128 varints (zigzag encoded when signed).
+// Spans are four varints: start line and column, end line and column.
+
+use std::fmt;
+
+use super::{cell_mask, Cell, Op, Program};
+use crate::{
+    cli::{CellOverflow, UNBOUNDED_CELL_BITS},
+    span::{Position, Span},
+};
+
+const MAGIC: &[u8; 4] = b"BFC\0";
+const VERSION: u8 = 4;
+
+const OP_ADD: u8 = 0;
+const OP_SET: u8 = 1;
+const OP_MOVE: u8 = 2;
+const OP_MUL_ADD: u8 = 3;
+const OP_SCAN: u8 = 4;
+const OP_OUTPUT: u8 = 5;
+const OP_INPUT: u8 = 6;
+const OP_JUMP_IF_ZERO: u8 = 7;
+const OP_JUMP_IF_NON_ZERO: u8 = 8;
+const OP_JUMP_IF_NEGATIVE: u8 = 9;
+
+#[derive(Debug)]
+pub enum FormatError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnsupportedCellBits(u8),
+    UnsupportedCellOverflow(u8),
+    UnexpectedEnd,
+    UnknownOpcode(u8),
+    InvalidOperand,
+    InvalidJump(u32),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::BadMagic => write!(f, "not a rustfuck bytecode file"),
+            FormatError::UnsupportedVersion(version) => {
+                write!(f, "unsupported bytecode version {version}")
+            }
+            FormatError::UnsupportedCellBits(bits) => write!(f, "unsupported cell width {bits}"),
+            FormatError::UnsupportedCellOverflow(overflow) => {
+                write!(f, "unsupported cell overflow mode {overflow}")
+            }
+            FormatError::UnexpectedEnd => write!(f, "unexpected end of bytecode"),
+            FormatError::UnknownOpcode(opcode) => write!(f, "unknown opcode {opcode}"),
+            FormatError::InvalidOperand => write!(f, "operand out of range"),
+            FormatError::InvalidJump(target) => write!(f, "jump target {target} out of range"),
+        }
+    }
+}
+
+impl Program {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        bytes.push(self.cell_bits as u8);
+        bytes.push(match self.cell_overflow {
+            CellOverflow::Wrap => 0,
+            CellOverflow::Saturate => 1,
+            CellOverflow::Trap => 2,
+        });
+        bytes.push(self.cell_signed as u8);
+        write_unsigned(&mut bytes, self.ops.len() as u64);
+
+        for op in &self.ops {
+            match *op {
+                Op::Add { offset, value } => {
+                    bytes.push(OP_ADD);
+                    write_signed(&mut bytes, offset as i64);
+                    write_unsigned(&mut bytes, value);
+                }
+                Op::Set { offset, value } => {
+                    bytes.push(OP_SET);
+                    write_signed(&mut bytes, offset as i64);
+                    write_unsigned(&mut bytes, value);
+                }
+                Op::Move(amount) => {
+                    bytes.push(OP_MOVE);
+                    write_signed(&mut bytes, amount as i64);
+                }
+                Op::MulAdd { offset, factor } => {
+                    bytes.push(OP_MUL_ADD);
+                    write_signed(&mut bytes, offset as i64);
+                    write_unsigned(&mut bytes, factor);
+                }
+                Op::Scan(step) => {
+                    bytes.push(OP_SCAN);
+                    write_signed(&mut bytes, step as i64);
+                }
+                Op::Output { offset } => {
+                    bytes.push(OP_OUTPUT);
+                    write_signed(&mut bytes, offset as i64);
+                }
+                Op::Input { offset } => {
+                    bytes.push(OP_INPUT);
+                    write_signed(&mut bytes, offset as i64);
+                }
+                Op::JumpIfZero(target) => {
+                    bytes.push(OP_JUMP_IF_ZERO);
+                    write_unsigned(&mut bytes, target as u64);
+                }
+                Op::JumpIfNonZero(target) => {
+                    bytes.push(OP_JUMP_IF_NON_ZERO);
+                    write_unsigned(&mut bytes, target as u64);
+                }
+                Op::JumpIfNegative(target) => {
+                    bytes.push(OP_JUMP_IF_NEGATIVE);
+                    write_unsigned(&mut bytes, target as u64);
+                }
+            }
+        }
+
+        for span in &self.spans {
+            write_unsigned(&mut bytes, span.start.line as u64);
+            write_unsigned(&mut bytes, span.start.column as u64);
+            write_unsigned(&mut bytes, span.end.line as u64);
+            write_unsigned(&mut bytes, span.end.column as u64);
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FormatError> {
+        let mut reader = Reader {
+            bytes,
+            i: 0,
+            mask: 0,
+        };
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(FormatError::BadMagic);
+        }
+
+        let version = reader.byte()?;
+        if version != VERSION {
+            return Err(FormatError::UnsupportedVersion(version));
+        }
+
+        let cell_bits = reader.byte()?;
+        if ![8, 16, 32, 64, UNBOUNDED_CELL_BITS as u8].contains(&cell_bits) {
+            return Err(FormatError::UnsupportedCellBits(cell_bits));
+        }
+        reader.mask = cell_mask(cell_bits as u32);
+
+        let cell_overflow = match reader.byte()? {
+            0 => CellOverflow::Wrap,
+            1 => CellOverflow::Saturate,
+            2 => CellOverflow::Trap,
+            overflow => return Err(FormatError::UnsupportedCellOverflow(overflow)),
+        };
+        let cell_signed = match reader.byte()? {
+            0 => false,
+            1 => true,
+            _ => return Err(FormatError::InvalidOperand),
+        };
+
+        let count = reader.unsigned()?;
+        let mut ops = vec![];
+
+        for _ in 0..count {
+            let op = match reader.byte()? {
+                // Cells that don't wrap add any amount
+                OP_ADD if cell_overflow != CellOverflow::Wrap => Op::Add {
+                    offset: reader.offset()?,
+                    value: reader.unsigned()?,
+                },
+                OP_ADD => Op::Add {
+                    offset: reader.offset()?,
+                    value: reader.value()?,
+                },
+                OP_SET => Op::Set {
+                    offset: reader.offset()?,
+                    value: reader.value()?,
+                },
+                OP_MOVE => Op::Move(reader.offset()?),
+                OP_MUL_ADD => Op::MulAdd {
+                    offset: reader.offset()?,
+                    factor: reader.value()?,
+                },
+                OP_SCAN => Op::Scan(reader.offset()?),
+                OP_OUTPUT => Op::Output {
+                    offset: reader.offset()?,
+                },
+                OP_INPUT => Op::Input {
+                    offset: reader.offset()?,
+                },
+                OP_JUMP_IF_ZERO => Op::JumpIfZero(reader.target()?),
+                OP_JUMP_IF_NON_ZERO => Op::JumpIfNonZero(reader.target()?),
+                OP_JUMP_IF_NEGATIVE => Op::JumpIfNegative(reader.target()?),
+                opcode => return Err(FormatError::UnknownOpcode(opcode)),
+            };
+
+            ops.push(op);
+        }
+
+        let mut spans = vec![];
+        for _ in 0..count {
+            let start = Position::new(reader.position()?, reader.position()?);
+            let end = Position::new(reader.position()?, reader.position()?);
+            spans.push(Span::new(start, end));
+        }
+
+        for op in &ops {
+            if let Op::JumpIfZero(target) | Op::JumpIfNonZero(target) | Op::JumpIfNegative(target) =
+                *op
+            {
+                if target as usize > ops.len() {
+                    return Err(FormatError::InvalidJump(target));
+                }
+            }
+        }
+
+        Ok(Program {
+            ops,
+            spans,
+            cell_bits: cell_bits as u32,
+            cell_overflow,
+            cell_signed,
+        })
+    }
+}
+
+fn write_unsigned(bytes: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn write_signed(bytes: &mut Vec<u8>, value: i64) {
+    write_unsigned(bytes, ((value << 1) ^ (value >> 63)) as u64);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    i: usize,
+    // Values have to fit in a cell
+    mask: Cell,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, amount: usize) -> Result<&'a [u8], FormatError> {
+        let slice = self
+            .bytes
+            .get(self.i..self.i + amount)
+            .ok_or(FormatError::UnexpectedEnd)?;
+        self.i += amount;
+        Ok(slice)
+    }
+
+    fn byte(&mut self) -> Result<u8, FormatError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn unsigned(&mut self) -> Result<u64, FormatError> {
+        let mut value = 0;
+        let mut shift = 0;
+
+        loop {
+            let byte = self.byte()?;
+            if shift >= 64 {
+                return Err(FormatError::InvalidOperand);
+            }
+
+            value |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn signed(&mut self) -> Result<i64, FormatError> {
+        let value = self.unsigned()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    fn offset(&mut self) -> Result<i32, FormatError> {
+        i32::try_from(self.signed()?).map_err(|_| FormatError::InvalidOperand)
+    }
+
+    fn value(&mut self) -> Result<Cell, FormatError> {
+        let value = self.unsigned()?;
+
+        if value & !self.mask != 0 {
+            return Err(FormatError::InvalidOperand);
+        }
+
+        Ok(value)
+    }
+
+    fn position(&mut self) -> Result<usize, FormatError> {
+        usize::try_from(self.unsigned()?).map_err(|_| FormatError::InvalidOperand)
+    }
+
+    fn target(&mut self) -> Result<u32, FormatError> {
+        u32::try_from(self.unsigned()?).map_err(|_| FormatError::InvalidOperand)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program() -> Program {
+        let ops = vec![
+            Op::Add {
+                offset: -3,
+                value: 255,
+            },
+            Op::JumpIfZero(7),
+            Op::Set {
+                offset: 1,
+                value: 0,
+            },
+            Op::Move(-200),
+            Op::MulAdd {
+                offset: 2,
+                factor: 254,
+            },
+            Op::Scan(-1),
+            Op::JumpIfNonZero(1),
+            Op::Output { offset: 0 },
+            Op::Input { offset: 5 },
+        ];
+        let spans = (1..=ops.len())
+            .map(|column| {
+                Span::new(
+                    Position::new(1, column),
+                    Position::new(column * 300, column),
+                )
+            })
+            .collect();
+
+        Program {
+            ops,
+            spans,
+            cell_bits: 8,
+            cell_overflow: CellOverflow::Wrap,
+            cell_signed: true,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let program = program();
+        let read = Program::from_bytes(&program.to_bytes()).unwrap();
+
+        assert_eq!(read.ops, program.ops);
+        assert_eq!(read.spans, program.spans);
+        assert_eq!(read.cell_bits, 8);
+        assert_eq!(read.cell_overflow, CellOverflow::Wrap);
+        assert!(read.cell_signed);
+    }
+
+    #[test]
+    fn round_trip_unbounded() {
+        let program = Program {
+            ops: vec![Op::Add {
+                offset: 0,
+                value: u64::MAX,
+            }],
+            spans: vec![Span::new(Position::new(1, 1), Position::new(1, 1))],
+            cell_bits: UNBOUNDED_CELL_BITS,
+            cell_overflow: CellOverflow::Trap,
+            cell_signed: false,
+        };
+        let read = Program::from_bytes(&program.to_bytes()).unwrap();
+
+        assert_eq!(read.ops, program.ops);
+        assert_eq!(read.cell_bits, UNBOUNDED_CELL_BITS);
+        assert_eq!(read.cell_overflow, CellOverflow::Trap);
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut bytes = program().to_bytes();
+        bytes[0] = b'X';
+
+        assert!(matches!(
+            Program::from_bytes(&bytes),
+            Err(FormatError::BadMagic)
+        ));
+        assert!(matches!(
+            Program::from_bytes(b"BF"),
+            Err(FormatError::UnexpectedEnd)
+        ));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut bytes = program().to_bytes();
+        bytes[MAGIC.len()] = VERSION + 1;
+
+        assert!(matches!(
+            Program::from_bytes(&bytes),
+            Err(FormatError::UnsupportedVersion(version)) if version == VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn unsupported_header() {
+        let mut bytes = program().to_bytes();
+        bytes[MAGIC.len() + 1] = 12;
+        assert!(matches!(
+            Program::from_bytes(&bytes),
+            Err(FormatError::UnsupportedCellBits(12))
+        ));
+
+        let mut bytes = program().to_bytes();
+        bytes[MAGIC.len() + 2] = 3;
+        assert!(matches!(
+            Program::from_bytes(&bytes),
+            Err(FormatError::UnsupportedCellOverflow(3))
+        ));
+    }
+
+    #[test]
+    fn value_wider_than_cell() {
+        let mut program = program();
+        program.ops[0] = Op::Add {
+            offset: 0,
+            value: 256,
+        };
+
+        assert!(matches!(
+            Program::from_bytes(&program.to_bytes()),
+            Err(FormatError::InvalidOperand)
+        ));
+    }
+
+    #[test]
+    fn jump_out_of_range() {
+        let mut program = program();
+        program.ops[1] = Op::JumpIfZero(100);
+
+        assert!(matches!(
+            Program::from_bytes(&program.to_bytes()),
+            Err(FormatError::InvalidJump(100))
+        ));
+    }
+
+    #[test]
+    fn unknown_opcode() {
+        let mut bytes = program().to_bytes();
+        // The first op comes right after the header and the count
+        bytes[MAGIC.len() + 5] = 200;
+
+        assert!(matches!(
+            Program::from_bytes(&bytes),
+            Err(FormatError::UnknownOpcode(200))
+        ));
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = program().to_bytes();
+
+        for length in 0..bytes.len() {
+            assert!(
+                matches!(
+                    Program::from_bytes(&bytes[..length]),
+                    Err(FormatError::UnexpectedEnd)
+                ),
+                "{length} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn varints() {
+        for value in [0, 1, 127, 128, 300, u64::MAX] {
+            let mut bytes = vec![];
+            write_unsigned(&mut bytes, value);
+            let mut reader = Reader {
+                bytes: &bytes,
+                i: 0,
+                mask: u64::MAX,
+            };
+            assert_eq!(reader.unsigned().unwrap(), value);
+        }
+
+        for value in [0, -1, 1, -64, 64, i64::MIN, i64::MAX] {
+            let mut bytes = vec![];
+            write_signed(&mut bytes, value);
+            let mut reader = Reader {
+                bytes: &bytes,
+                i: 0,
+                mask: u64::MAX,
+            };
+            assert_eq!(reader.signed().unwrap(), value);
+        }
+    }
+}
@@ -0,0 +1,115 @@
+mod bignum;
+mod compiler;
+mod format;
+mod vm;
+
+pub use bignum::BigInt;
+pub use compiler::compile;
+pub use vm::{Config, Limits, Observer, Vm, VmError};
+
+// Cells of every width are stored in a u64 and wrapped to `cell_bits`.
+// Unbounded cells are `BigInt`s instead, but values in their ops are still
+// Cells: two's complement i64s that are never wrapped.
+pub type Cell = u64;
+
+pub const DEFAULT_CELL_BITS: u32 = 8;
+
+// All bits of a cell that is `bits` wide
+pub fn cell_mask(bits: u32) -> Cell {
+    if bits == UNBOUNDED_CELL_BITS {
+        return Cell::MAX;
+    }
+
+    Cell::MAX >> (Cell::BITS - bits)
+}
+
+// Smallest and largest value of a cell
+pub fn cell_range(bits: u32, signed: bool) -> (i128, i128) {
+    if signed {
+        (-(1 << (bits - 1)), (1 << (bits - 1)) - 1)
+    } else {
+        (0, cell_mask(bits) as i128)
+    }
+}
+
+// The value of a cell, which is negative if its top bit is set and it's signed
+pub fn cell_value(cell: Cell, bits: u32, signed: bool) -> i128 {
+    if signed && cell >> (bits - 1) & 1 == 1 {
+        cell as i128 - (cell_mask(bits) as i128 + 1)
+    } else {
+        cell as i128
+    }
+}
+
+use crate::{
+    cli::{CellOverflow, UNBOUNDED_CELL_BITS},
+    span::Span,
+};
+
+// Offsets are relative to the data pointer, jump targets are op indices. The
+// value of an `Add` wraps to the cell width, unless cells don't wrap: then it's
+// the amount to add as a two's complement i64.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Add { offset: i32, value: Cell },
+    Set { offset: i32, value: Cell },
+    Move(i32),
+    // cell[offset] += cell[0] * factor
+    MulAdd { offset: i32, factor: Cell },
+    // Move by `step` until the current cell is zero
+    Scan(i32),
+    Output { offset: i32 },
+    Input { offset: i32 },
+    JumpIfZero(u32),
+    JumpIfNonZero(u32),
+    // Only used with unbounded cells, to skip a multiplied out loop when its
+    // counter is negative
+    JumpIfNegative(u32),
+}
+
+#[derive(Debug)]
+pub struct Program {
+    pub ops: Vec<Op>,
+    // Source span of every op
+    pub spans: Vec<Span>,
+    // Values are wrapped to this width, so the program only runs correctly
+    // with cells that are this wide
+    pub cell_bits: u32,
+    // Runs are only folded in ways that overflow like they would unfolded,
+    // which depends on these as well
+    pub cell_overflow: CellOverflow,
+    pub cell_signed: bool,
+}
+
+impl Default for Program {
+    fn default() -> Self {
+        Self {
+            ops: vec![],
+            spans: vec![],
+            cell_bits: DEFAULT_CELL_BITS,
+            cell_overflow: CellOverflow::Wrap,
+            cell_signed: false,
+        }
+    }
+}
+
+// Compiles the source like `rustfuck run` with the arguments
+#[cfg(test)]
+pub fn compile_source(source: &str, arguments: &[&str]) -> (Program, Config) {
+    use clap::Parser as CliParser;
+
+    use crate::{
+        cli::{Cli, Command},
+        codegen_options, parse_source, vm_config,
+    };
+
+    let cli = Cli::parse_from(["rustfuck", "run", "test.bf"].iter().chain(arguments));
+    let Some(Command::Run(args)) = cli.command else {
+        unreachable!()
+    };
+
+    let ast = parse_source("test.bf", source.to_string());
+    let options = codegen_options(args.optimizations, &args.machine);
+
+    (compile(&ast, &options), vm_config(&args.machine))
+}
@@ -0,0 +1,932 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    io::{Read, Write},
+    time::{Duration, Instant},
+};
+
+use super::{cell_mask, cell_range, cell_value, BigInt, Cell, Op, Program, DEFAULT_CELL_BITS};
+use crate::cli::{CellOverflow, EofMode, InputMode, TapeMode, UNBOUNDED_CELL_BITS};
+
+pub const TAPE_SIZE: usize = 30000;
+
+// How many ops to run between checking the clock
+const TIMEOUT_CHECK_INTERVAL: u64 = 1 << 16;
+
+// How programs behave, every field matches an option of the compiled backends
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub tape_size: usize,
+    pub tape: TapeMode,
+    pub cell_bits: u32,
+    pub cell_overflow: CellOverflow,
+    pub cell_signed: bool,
+    pub eof: EofMode,
+    pub input_mode: InputMode,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            tape_size: TAPE_SIZE,
+            tape: TapeMode::Fixed,
+            cell_bits: DEFAULT_CELL_BITS,
+            cell_overflow: CellOverflow::Wrap,
+            cell_signed: false,
+            eof: EofMode::MinusOne,
+            input_mode: InputMode::Raw,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Limits {
+    pub max_steps: Option<u64>,
+    pub max_output_bytes: Option<u64>,
+    pub timeout: Option<Duration>,
+    pub max_tape: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Steps,
+    OutputBytes,
+    Timeout,
+    Tape,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Steps => write!(f, "step limit (--max-steps)"),
+            Limit::OutputBytes => write!(f, "output limit (--max-output-bytes)"),
+            Limit::Timeout => write!(f, "time limit (--timeout)"),
+            Limit::Tape => write!(f, "tape limit (--max-tape)"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum VmError {
+    Io(io::Error),
+    PointerOutOfRange { pointer: isize },
+    // `+` or `-` went past the limits of the cell with --cell-overflow trap
+    CellOverflow { pointer: isize },
+    LimitExceeded(Limit),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::Io(error) => write!(f, "I/O error: {error}"),
+            VmError::PointerOutOfRange { pointer } => {
+                write!(f, "pointer out of range, pointer = {pointer}")
+            }
+            VmError::CellOverflow { pointer } => {
+                write!(f, "cell overflow, pointer = {pointer}")
+            }
+            VmError::LimitExceeded(limit) => write!(f, "{limit} exceeded"),
+        }
+    }
+}
+
+impl From<io::Error> for VmError {
+    fn from(error: io::Error) -> Self {
+        VmError::Io(error)
+    }
+}
+
+// Every op is decoded into the address of the function that executes it
+// together with its operands. The dispatch loop calls through the handler
+// directly instead of matching on an opcode (direct threading).
+type Handler = for<'a, 'b> fn(&'b mut Vm<'a>, Instr, usize) -> Result<usize, VmError>;
+
+#[derive(Clone, Copy)]
+struct Instr {
+    handler: Handler,
+    offset: isize,
+    arg: isize,
+}
+
+// Hooks around every op for tools such as the profiler. `pc` is the index
+// of the op that is about to run, or that just ran.
+pub trait Observer {
+    fn before(&mut self, _vm: &Vm, _pc: usize) {}
+    fn after(&mut self, _vm: &Vm, _pc: usize) {}
+}
+
+// Sessions are a list of what `,` returned, in the same format as the
+// `--record` and `--replay` options of compiled programs
+const SESSION_BYTE: u8 = 0;
+const SESSION_EOF: u8 = 1;
+
+pub struct Vm<'a> {
+    code: Vec<Instr>,
+    tape: Vec<Cell>,
+    // Unbounded cells that aren't 0 by index, instead of `tape`
+    cells: HashMap<isize, BigInt>,
+    // Where cell 0 is on the tape, the middle of a bidirectional tape
+    origin: usize,
+    // Arithmetic wraps to the cell width by masking the result
+    mask: Cell,
+    ptr: isize,
+    pc: usize,
+    steps: u64,
+    output_bytes: u64,
+    config: Config,
+    limits: Limits,
+    deadline: Option<Instant>,
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+    record: Option<&'a mut dyn Write>,
+    replay: Option<&'a mut dyn Read>,
+}
+
+impl<'a> Vm<'a> {
+    pub fn new(
+        program: &Program,
+        config: Config,
+        limits: Limits,
+        input: &'a mut dyn Read,
+        output: &'a mut dyn Write,
+    ) -> Self {
+        let tape_size = limits
+            .max_tape
+            .map_or(config.tape_size, |max| max.min(config.tape_size));
+
+        // --max-tape keeps the cells around cell 0
+        let origin = match config.tape {
+            TapeMode::Bidirectional => tape_size / 2,
+            _ => 0,
+        };
+
+        let tape_size = if config.cell_bits == UNBOUNDED_CELL_BITS {
+            0
+        } else {
+            tape_size
+        };
+
+        Self {
+            code: decode_all(program, &config),
+            tape: vec![0; tape_size],
+            cells: HashMap::new(),
+            origin,
+            mask: cell_mask(config.cell_bits),
+            ptr: 0,
+            pc: 0,
+            steps: 0,
+            output_bytes: 0,
+            deadline: limits.timeout.map(|timeout| Instant::now() + timeout),
+            config,
+            limits,
+            input,
+            output,
+            record: None,
+            replay: None,
+        }
+    }
+
+    // Saves what every `,` reads as a session
+    pub fn record_input(&mut self, record: &'a mut dyn Write) {
+        self.record = Some(record);
+    }
+
+    // Reads the input of `,` from a session instead of the input
+    pub fn replay_input(&mut self, replay: &'a mut dyn Read) {
+        self.replay = Some(replay);
+    }
+
+    // Replaces the program but keeps the tape and pointer
+    pub fn load(&mut self, program: &Program) {
+        self.code = decode_all(program, &self.config);
+        self.pc = 0;
+    }
+
+    pub fn reset(&mut self) {
+        self.tape.fill(0);
+        self.cells.clear();
+        self.ptr = 0;
+    }
+
+    pub fn run(&mut self) -> Result<(), VmError> {
+        let result = self.dispatch();
+        self.output.flush()?;
+        result
+    }
+
+    fn dispatch(&mut self) -> Result<(), VmError> {
+        while self.pc < self.code.len() {
+            self.check_limits()?;
+
+            let instr = self.code[self.pc];
+            self.pc = (instr.handler)(self, instr, self.pc)?;
+            self.steps += 1;
+        }
+
+        Ok(())
+    }
+
+    // Same as `run`, but calls the observers around every op
+    pub fn run_with(&mut self, observers: &mut [&mut dyn Observer]) -> Result<(), VmError> {
+        let mut result = Ok(());
+
+        while !self.is_finished() {
+            let pc = self.pc;
+
+            for observer in observers.iter_mut() {
+                observer.before(self, pc);
+            }
+
+            result = self.step();
+            if result.is_err() {
+                break;
+            }
+
+            for observer in observers.iter_mut() {
+                observer.after(self, pc);
+            }
+        }
+
+        self.output.flush()?;
+        result
+    }
+
+    fn check_limits(&self) -> Result<(), VmError> {
+        if self.limits.max_steps.is_some_and(|max| self.steps >= max) {
+            return Err(VmError::LimitExceeded(Limit::Steps));
+        }
+
+        if self.steps.is_multiple_of(TIMEOUT_CHECK_INTERVAL)
+            && self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Err(VmError::LimitExceeded(Limit::Timeout));
+        }
+
+        Ok(())
+    }
+
+    // Runs a single op
+    pub fn step(&mut self) -> Result<(), VmError> {
+        self.check_limits()?;
+
+        let instr = self.code[self.pc];
+        self.pc = (instr.handler)(self, instr, self.pc)?;
+        self.steps += 1;
+        Ok(())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.pc >= self.code.len()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    // Index of the op that is about to run, or that failed
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn pointer(&self) -> isize {
+        self.ptr
+    }
+
+    // Empty with unbounded cells, see `unbounded_cell`
+    pub fn tape(&self) -> &[Cell] {
+        &self.tape
+    }
+
+    pub fn is_unbounded(&self) -> bool {
+        self.config.cell_bits == UNBOUNDED_CELL_BITS
+    }
+
+    // Number of cells on the tape, including unbounded cells that are 0
+    pub fn tape_len(&self) -> usize {
+        if !self.is_unbounded() {
+            return self.tape.len();
+        }
+
+        let used = self
+            .cells
+            .keys()
+            .max()
+            .map_or(0, |index| (index + self.origin as isize + 1) as usize);
+        used.max(self.config.tape_size)
+    }
+
+    // None unless cells are unbounded
+    pub fn unbounded_cell(&self, index: isize) -> Option<BigInt> {
+        if !self.is_unbounded() || self.cell_index(index).is_err() {
+            return None;
+        }
+
+        Some(self.cells.get(&index).cloned().unwrap_or_default())
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn cell_bits(&self) -> u32 {
+        self.config.cell_bits
+    }
+
+    // The width of cells when they are signed, for showing them
+    pub fn signed_bits(&self) -> Option<u32> {
+        self.config.cell_signed.then_some(self.config.cell_bits)
+    }
+
+    // Index of the first cell on the tape, negative on a bidirectional tape
+    pub fn first_cell(&self) -> isize {
+        -(self.origin as isize)
+    }
+
+    pub fn cell_at(&self, index: isize) -> Option<Cell> {
+        usize::try_from(index + self.origin as isize)
+            .ok()
+            .and_then(|index| self.tape.get(index).copied())
+    }
+
+    // Cells that aren't on the tape are ignored
+    pub fn set_cell(&mut self, index: isize, value: Cell) {
+        if let Ok(index) = usize::try_from(index + self.origin as isize) {
+            if let Some(cell) = self.tape.get_mut(index) {
+                *cell = value;
+            }
+        }
+    }
+
+    // Puts the VM back into an earlier state, the tape is restored separately
+    pub fn restore(&mut self, pc: usize, pointer: isize, steps: u64) {
+        self.pc = pc;
+        self.ptr = pointer;
+        self.steps = steps;
+    }
+
+    // Replaces the whole tape, a growable tape may have been shorter
+    pub fn set_tape(&mut self, tape: &[Cell]) {
+        self.tape.clear();
+        self.tape.extend_from_slice(tape);
+    }
+
+    // Checks that the unbounded cell at `pointer` is on the tape, which has
+    // the same bounds as a tape of cells with a fixed width
+    fn cell_index(&self, pointer: isize) -> Result<isize, VmError> {
+        let size = match self.config.tape {
+            TapeMode::Growable => self.limits.max_tape.unwrap_or(usize::MAX),
+            _ => self
+                .limits
+                .max_tape
+                .map_or(self.config.tape_size, |max| max.min(self.config.tape_size)),
+        };
+
+        match usize::try_from(pointer + self.origin as isize) {
+            Ok(index) if index < size => Ok(pointer),
+            _ => Err(self.off_tape(pointer)),
+        }
+    }
+
+    // Why the cell at `pointer` isn't on the tape: it's off the tape the
+    // program was written for, or only past --max-tape
+    fn off_tape(&self, pointer: isize) -> VmError {
+        let origin = match self.config.tape {
+            TapeMode::Bidirectional => self.config.tape_size / 2,
+            _ => 0,
+        };
+
+        match usize::try_from(pointer + origin as isize) {
+            Ok(index)
+                if index < self.config.tape_size || self.config.tape == TapeMode::Growable =>
+            {
+                VmError::LimitExceeded(Limit::Tape)
+            }
+            _ => VmError::PointerOutOfRange { pointer },
+        }
+    }
+
+    fn big_cell(&self, offset: isize) -> Result<Option<&BigInt>, VmError> {
+        let index = self.cell_index(self.ptr + offset)?;
+        Ok(self.cells.get(&index))
+    }
+
+    // Cells that become 0 are removed to keep the tape sparse
+    fn update_big_cell(
+        &mut self,
+        offset: isize,
+        update: impl FnOnce(&mut BigInt),
+    ) -> Result<(), VmError> {
+        let index = self.cell_index(self.ptr + offset)?;
+        let cell = self.cells.entry(index).or_default();
+        update(cell);
+
+        if cell.is_zero() {
+            self.cells.remove(&index);
+        }
+        Ok(())
+    }
+
+    fn cell(&mut self, offset: isize) -> Result<&mut Cell, VmError> {
+        let pointer = self.ptr + offset;
+
+        match usize::try_from(pointer + self.origin as isize) {
+            Ok(index) if index < self.tape.len() => Ok(&mut self.tape[index]),
+            Ok(index) if self.config.tape == TapeMode::Growable => {
+                self.grow(index)?;
+                Ok(&mut self.tape[index])
+            }
+            _ => Err(self.off_tape(pointer)),
+        }
+    }
+
+    // Grows the tape so `index` is on it, doubling it like the compiled
+    // programs do
+    fn grow(&mut self, index: usize) -> Result<(), VmError> {
+        if self.limits.max_tape.is_some_and(|max| index >= max) {
+            return Err(VmError::LimitExceeded(Limit::Tape));
+        }
+
+        let size = (self.tape.len() * 2).max(index + 1);
+        let size = self.limits.max_tape.map_or(size, |max| size.min(max));
+        self.tape.resize(size, 0);
+        Ok(())
+    }
+
+    fn read_byte(input: &mut dyn Read) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+
+        loop {
+            match input.read(&mut byte) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(byte[0])),
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    // Same as `get_character` in the C and asm runtimes: read a character
+    // and in line mode throw away the rest of the line
+    fn get_character(&mut self) -> io::Result<Option<u8>> {
+        self.output.flush()?;
+
+        let Some(character) = Self::read_byte(self.input)? else {
+            return Ok(None);
+        };
+
+        if self.config.input_mode == InputMode::Raw {
+            return Ok(Some(character));
+        }
+
+        let mut tmp = character;
+        while tmp != b'\n' {
+            match Self::read_byte(self.input)? {
+                Some(byte) => tmp = byte,
+                None => break,
+            }
+        }
+
+        Ok(Some(character))
+    }
+
+    // Reads the next character, from the replayed session if there is one
+    fn next_input(&mut self) -> io::Result<Option<u8>> {
+        let character = match &mut self.replay {
+            Some(replay) => match Self::read_byte(*replay)? {
+                Some(SESSION_BYTE) => Self::read_byte(*replay)?,
+                _ => None,
+            },
+            None => self.get_character()?,
+        };
+
+        if let Some(record) = &mut self.record {
+            match character {
+                Some(byte) => record.write_all(&[SESSION_BYTE, byte])?,
+                None => record.write_all(&[SESSION_EOF])?,
+            }
+            record.flush()?;
+        }
+
+        Ok(character)
+    }
+}
+
+fn decode_all(program: &Program, config: &Config) -> Vec<Instr> {
+    program.ops.iter().map(|op| decode(op, config)).collect()
+}
+
+fn decode(op: &Op, config: &Config) -> Instr {
+    if config.cell_bits == UNBOUNDED_CELL_BITS {
+        return decode_unbounded(op);
+    }
+
+    let (handler, offset, arg): (Handler, i32, isize) = match *op {
+        Op::Add { offset, value } if config.cell_overflow != CellOverflow::Wrap => {
+            (op_add_checked, offset, value as isize)
+        }
+        Op::Add { offset, value } => (op_add, offset, value as isize),
+        Op::Set { offset, value } => (op_set, offset, value as isize),
+        Op::Move(amount) => (op_move, 0, amount as isize),
+        Op::MulAdd { offset, factor } => (op_mul_add, offset, factor as isize),
+        Op::Scan(step) => (op_scan, 0, step as isize),
+        Op::Output { offset } => (op_output, offset, 0),
+        Op::Input { offset } => (op_input, offset, 0),
+        Op::JumpIfZero(target) => (op_jump_if_zero, 0, target as isize),
+        Op::JumpIfNonZero(target) => (op_jump_if_non_zero, 0, target as isize),
+        Op::JumpIfNegative(target) => (op_jump_if_negative, 0, target as isize),
+    };
+
+    Instr {
+        handler,
+        offset: offset as isize,
+        arg,
+    }
+}
+
+// Values are two's complement i64s, which fit `arg`
+fn decode_unbounded(op: &Op) -> Instr {
+    let (handler, offset, arg): (Handler, i32, isize) = match *op {
+        Op::Add { offset, value } => (op_add_unbounded, offset, value as isize),
+        Op::Set { offset, value } => (op_set_unbounded, offset, value as isize),
+        Op::Move(amount) => (op_move, 0, amount as isize),
+        Op::MulAdd { offset, factor } => (op_mul_add_unbounded, offset, factor as isize),
+        Op::Scan(step) => (op_scan_unbounded, 0, step as isize),
+        Op::Output { offset } => (op_output_unbounded, offset, 0),
+        Op::Input { offset } => (op_input_unbounded, offset, 0),
+        Op::JumpIfZero(target) => (op_jump_if_zero_unbounded, 0, target as isize),
+        Op::JumpIfNonZero(target) => (op_jump_if_non_zero_unbounded, 0, target as isize),
+        Op::JumpIfNegative(target) => (op_jump_if_negative_unbounded, 0, target as isize),
+    };
+
+    Instr {
+        handler,
+        offset: offset as isize,
+        arg,
+    }
+}
+
+fn op_add(vm: &mut Vm, instr: Instr, pc: usize) -> Result<usize, VmError> {
+    let mask = vm.mask;
+    let cell = vm.cell(instr.offset)?;
+    *cell = cell.wrapping_add(instr.arg as Cell) & mask;
+    Ok(pc + 1)
+}
+
+// `arg` is the amount to add, the cell saturates or traps instead of wrapping
+fn op_add_checked(vm: &mut Vm, instr: Instr, pc: usize) -> Result<usize, VmError> {
+    let (bits, signed) = (vm.config.cell_bits, vm.config.cell_signed);
+    let (min, max) = cell_range(bits, signed);
+    let overflow = vm.config.cell_overflow;
+    let pointer = vm.ptr + instr.offset;
+    let mask = vm.mask;
+
+    let cell = vm.cell(instr.offset)?;
+    let sum = cell_value(*cell, bits, signed) + instr.arg as i128;
+
+    if !(min..=max).contains(&sum) && overflow == CellOverflow::Trap {
+        return Err(VmError::CellOverflow { pointer });
+    }
+
+    *cell = sum.clamp(min, max) as Cell & mask;
+    Ok(pc + 1)
+}
+
+fn op_set(vm: &mut Vm, instr: Instr, pc: usize) -> Result<usize, VmError> {
+    *vm.cell(instr.offset)? = instr.arg as Cell;
+    Ok(pc + 1)
+}
+
+fn op_move(vm: &mut Vm, instr: Instr, pc: usize) -> Result<usize, VmError> {
+    vm.ptr += instr.arg;
+    Ok(pc + 1)
+}
+
+fn op_mul_add(vm: &mut Vm, instr: Instr, pc: usize) -> Result<usize, VmError> {
+    let value = *vm.cell(0)?;
+    if value != 0 {
+        let mask = vm.mask;
+        let cell = vm.cell(instr.offset)?;
+        *cell = cell.wrapping_add(value.wrapping_mul(instr.arg as Cell)) & mask;
+    }
+    Ok(pc + 1)
+}
+
+fn op_scan(vm: &mut Vm, instr: Instr, pc: usize) -> Result<usize, VmError> {
+    while *vm.cell(0)? != 0 {
+        vm.ptr += instr.arg;
+    }
+    Ok(pc + 1)
+}
+
+fn op_output(vm: &mut Vm, instr: Instr, pc: usize) -> Result<usize, VmError> {
+    if vm
+        .limits
+        .max_output_bytes
+        .is_some_and(|max| vm.output_bytes >= max)
+    {
+        return Err(VmError::LimitExceeded(Limit::OutputBytes));
+    }
+
+    // Only the low byte is written, like `putchar`
+    let value = *vm.cell(instr.offset)? as u8;
+    vm.output.write_all(&[value])?;
+    vm.output_bytes += 1;
+    Ok(pc + 1)
+}
+
+fn op_input(vm: &mut Vm, instr: Instr, pc: usize) -> Result<usize, VmError> {
+    let character = vm.next_input()?;
+    let (eof, mask) = (vm.config.eof, vm.mask);
+    let cell = vm.cell(instr.offset)?;
+
+    match (character, eof) {
+        (Some(character), _) => *cell = Cell::from(character),
+        (None, EofMode::Unchanged) => {}
+        (None, EofMode::Zero) => *cell = 0,
+        (None, EofMode::MinusOne) => *cell = mask,
+    }
+    Ok(pc + 1)
+}
+
+fn op_jump_if_zero(vm: &mut Vm, instr: Instr, pc: usize) -> Result<usize, VmError> {
+    if *vm.cell(0)? == 0 {
+        Ok(instr.arg as usize)
+    } else {
+        Ok(pc + 1)
+    }
+}
+
+fn op_jump_if_non_zero(vm: &mut Vm, instr: Instr, pc: usize) -> Result<usize, VmError> {
+    if *vm.cell(0)? != 0 {
+        Ok(instr.arg as usize)
+    } else {
+        Ok(pc + 1)
+    }
+}
+
+fn op_jump_if_negative(vm: &mut Vm, instr: Instr, pc: usize) -> Result<usize, VmError> {
+    let (bits, signed) = (vm.config.cell_bits, vm.config.cell_signed);
+    if cell_value(*vm.cell(0)?, bits, signed) < 0 {
+        Ok(instr.arg as usize)
+    } else {
+        Ok(pc + 1)
+    }
+}
+
+fn is_zero_unbounded(vm: &Vm, offset: isize) -> Result<bool, VmError> {
+    Ok(vm.big_cell(offset)?.is_none())
+}
+
+fn op_add_unbounded(vm: &mut Vm, instr: Instr, pc: usize) -> Result<usize, VmError> {
+    vm.update_big_cell(instr.offset, |cell| cell.add(instr.arg as i64))?;
+    Ok(pc + 1)
+}
+
+fn op_set_unbounded(vm: &mut Vm, instr: Instr, pc: usize) -> Result<usize, VmError> {
+    vm.update_big_cell(instr.offset, |cell| *cell = BigInt::from(instr.arg as i64))?;
+    Ok(pc + 1)
+}
+
+fn op_mul_add_unbounded(vm: &mut Vm, instr: Instr, pc: usize) -> Result<usize, VmError> {
+    if let Some(value) = vm.big_cell(0)?.cloned() {
+        vm.update_big_cell(instr.offset, |cell| cell.mul_add(&value, instr.arg as i64))?;
+    }
+    Ok(pc + 1)
+}
+
+fn op_scan_unbounded(vm: &mut Vm, instr: Instr, pc: usize) -> Result<usize, VmError> {
+    while !is_zero_unbounded(vm, 0)? {
+        vm.ptr += instr.arg;
+    }
+    Ok(pc + 1)
+}
+
+fn op_output_unbounded(vm: &mut Vm, instr: Instr, pc: usize) -> Result<usize, VmError> {
+    if vm
+        .limits
+        .max_output_bytes
+        .is_some_and(|max| vm.output_bytes >= max)
+    {
+        return Err(VmError::LimitExceeded(Limit::OutputBytes));
+    }
+
+    let value = vm.big_cell(instr.offset)?.map_or(0, BigInt::low_byte);
+    vm.output.write_all(&[value])?;
+    vm.output_bytes += 1;
+    Ok(pc + 1)
+}
+
+fn op_input_unbounded(vm: &mut Vm, instr: Instr, pc: usize) -> Result<usize, VmError> {
+    let character = vm.next_input()?;
+    let value = match (character, vm.config.eof) {
+        (Some(character), _) => character as i64,
+        (None, EofMode::Unchanged) => return Ok(pc + 1),
+        (None, EofMode::Zero) => 0,
+        (None, EofMode::MinusOne) => -1,
+    };
+
+    vm.update_big_cell(instr.offset, |cell| *cell = BigInt::from(value))?;
+    Ok(pc + 1)
+}
+
+fn op_jump_if_zero_unbounded(vm: &mut Vm, instr: Instr, pc: usize) -> Result<usize, VmError> {
+    if is_zero_unbounded(vm, 0)? {
+        Ok(instr.arg as usize)
+    } else {
+        Ok(pc + 1)
+    }
+}
+
+fn op_jump_if_non_zero_unbounded(vm: &mut Vm, instr: Instr, pc: usize) -> Result<usize, VmError> {
+    if !is_zero_unbounded(vm, 0)? {
+        Ok(instr.arg as usize)
+    } else {
+        Ok(pc + 1)
+    }
+}
+
+fn op_jump_if_negative_unbounded(vm: &mut Vm, instr: Instr, pc: usize) -> Result<usize, VmError> {
+    if vm.big_cell(0)?.is_some_and(BigInt::is_negative) {
+        Ok(instr.arg as usize)
+    } else {
+        Ok(pc + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::compile_source;
+    use crate::span::{Position, Span};
+
+    const HELLO_WORLD: &str = include_str!("../../brainfucks/hello_world.bf");
+
+    fn run(
+        source: &str,
+        arguments: &[&str],
+        limits: Limits,
+        input: &[u8],
+    ) -> (Result<(), VmError>, Vec<u8>) {
+        let (program, config) = compile_source(source, arguments);
+
+        let mut input = input;
+        let mut output = vec![];
+        let result = Vm::new(&program, config, limits, &mut input, &mut output).run();
+
+        (result, output)
+    }
+
+    #[test]
+    fn hello_world() {
+        for arguments in [&[][..], &["-O"]] {
+            let (result, output) = run(HELLO_WORLD, arguments, Limits::default(), b"");
+
+            assert!(result.is_ok());
+            assert_eq!(output, b"Hello World!\n");
+        }
+    }
+
+    #[test]
+    fn limits() {
+        let limit = |source, limits| match run(source, &[], limits, b"") {
+            (Err(VmError::LimitExceeded(limit)), _) => limit,
+            (result, _) => panic!("expected a limit, got {result:?}"),
+        };
+
+        let steps = Limits {
+            max_steps: Some(1000),
+            ..Limits::default()
+        };
+        assert_eq!(limit("+[]", steps), Limit::Steps);
+
+        let output_bytes = Limits {
+            max_output_bytes: Some(3),
+            ..Limits::default()
+        };
+        assert_eq!(limit("+[.]", output_bytes), Limit::OutputBytes);
+
+        let timeout = Limits {
+            timeout: Some(Duration::from_millis(10)),
+            ..Limits::default()
+        };
+        assert_eq!(limit("+[]", timeout), Limit::Timeout);
+
+        let tape = Limits {
+            max_tape: Some(10),
+            ..Limits::default()
+        };
+        assert_eq!(limit("+[>+]", tape), Limit::Tape);
+    }
+
+    #[test]
+    fn output_limit_keeps_the_output() {
+        let limits = Limits {
+            max_output_bytes: Some(3),
+            ..Limits::default()
+        };
+        let (_, output) = run("+[.]", &[], limits, b"");
+
+        assert_eq!(output, [1, 1, 1]);
+    }
+
+    #[test]
+    fn scan_faults_at_the_end_of_the_loop() {
+        // Where compiled programs check the cell after every move
+        let (program, _) = compile_source("+[<]", &["-O"]);
+
+        assert_eq!(program.ops[1], Op::Scan(-1));
+        assert_eq!(program.spans[1], Span::at(Position::new(1, 4)));
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let (program, config) = compile_source(",[.,]", &["--eof", "zero"]);
+
+        let mut input = &b"abc"[..];
+        let mut output = vec![];
+        let mut session = vec![];
+        let mut vm = Vm::new(
+            &program,
+            config.clone(),
+            Limits::default(),
+            &mut input,
+            &mut output,
+        );
+        vm.record_input(&mut session);
+        vm.run().unwrap();
+        drop(vm);
+
+        assert_eq!(output, b"abc");
+        assert_eq!(session, [0, b'a', 0, b'b', 0, b'c', 1]);
+
+        // The input is ignored
+        let mut input = &b"xyz"[..];
+        let mut replayed = vec![];
+        let mut replay = &session[..];
+        let mut vm = Vm::new(
+            &program,
+            config,
+            Limits::default(),
+            &mut input,
+            &mut replayed,
+        );
+        vm.replay_input(&mut replay);
+        vm.run().unwrap();
+        drop(vm);
+
+        assert_eq!(replayed, output);
+    }
+
+    #[test]
+    fn pointer_out_of_range() {
+        let (result, _) = run("+<+", &[], Limits::default(), b"");
+
+        assert!(matches!(
+            result,
+            Err(VmError::PointerOutOfRange { pointer: -1 })
+        ));
+    }
+
+    #[test]
+    fn unbounded_multiplication() {
+        // Doubles a cell 40 times, which only finishes if the loops are
+        // multiplied out
+        let source = format!("+{}.", "[->++<]>[-<+>]<".repeat(40));
+        let limits = Limits {
+            max_steps: Some(10_000),
+            ..Limits::default()
+        };
+
+        let (result, output) = run(
+            &source,
+            &["-O", "--cell-bits", "unbounded"],
+            limits.clone(),
+            b"",
+        );
+        assert!(result.is_ok());
+        assert_eq!(output, [0]);
+
+        // 2^8 isn't 0 when cells are unbounded
+        let source = format!(
+            "+{}[[-]++++++++[->++++++++<]>+.<]",
+            "[->++<]>[-<+>]<".repeat(8)
+        );
+        let (result, output) = run(&source, &["-O", "--cell-bits", "unbounded"], limits, b"");
+        assert!(result.is_ok());
+        assert_eq!(output, b"A");
+    }
+
+    #[test]
+    fn unbounded_negative_loop_runs_unfolded() {
+        // Counting down from -1 never reaches 0
+        let limits = Limits {
+            max_steps: Some(1000),
+            ..Limits::default()
+        };
+        let (result, _) = run("-[->+<]", &["-O", "--cell-bits", "unbounded"], limits, b"");
+
+        assert!(matches!(result, Err(VmError::LimitExceeded(Limit::Steps))));
+    }
+}
@@ -1,11 +1,19 @@
-use clap::Parser;
+use clap::{
+    builder::{PossibleValuesParser, TypedValueParser},
+    Args, Parser, Subcommand, ValueEnum,
+};
 
 /// Brainfuck to x86_64 assembly or C Compiler
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Brainfuck source file
-    pub input_path: String,
+    #[arg(required = true)]
+    pub input_path: Option<String>,
 
     /// Output path
     #[arg(short = 'o', long)]
@@ -23,6 +31,18 @@ pub struct Cli {
     #[arg(short = 'C')]
     pub c: bool,
 
+    /// Output compiled bytecode
+    #[arg(short = 'B', long)]
+    pub bytecode: bool,
+
+    /// Output an object file that exports the program as a function
+    #[arg(short = 'c', conflicts_with_all = ["assembly", "c", "bytecode", "no_libc", "elf"])]
+    pub object: bool,
+
+    /// Name of the function an object file exports the program as
+    #[arg(long, value_name = "NAME", default_value = "bf_main", value_parser = parse_symbol)]
+    pub symbol: String,
+
     /// Keep intermediate files
     #[arg(long)]
     pub keep_files: bool,
@@ -30,4 +50,263 @@ pub struct Cli {
     /// Print generated AST
     #[arg(long = "ast")]
     pub dump_ast: bool,
+
+    /// Abort with the source location when the pointer leaves the tape
+    #[arg(long)]
+    pub bounds_check: bool,
+
+    /// Catch the pointer leaving the tape with guard pages instead of checks
+    #[arg(long, conflicts_with = "tape")]
+    pub guard_pages: bool,
+
+    /// Use system calls instead of libc in the assembly and link a static binary with `ld`
+    #[arg(long, conflicts_with_all = ["c", "bytecode"])]
+    pub no_libc: bool,
+
+    /// Write a static executable without `as` or `ld`, with the runtime of --no-libc
+    #[arg(long, conflicts_with_all = ["assembly", "c", "bytecode"])]
+    pub elf: bool,
+
+    /// Print the pointer and nearby cells on exit, SIGINT or SIGTERM
+    #[arg(long, conflicts_with = "bytecode")]
+    pub dump_tape_on_exit: bool,
+
+    /// Compile FILE into the program as its input instead of reading stdin
+    #[arg(long, value_name = "FILE", conflicts_with = "bytecode")]
+    pub bake_input: Option<String>,
+
+    #[command(flatten)]
+    pub machine: MachineArgs,
+}
+
+// Options that change how programs behave, shared by the compiler and the VM
+#[derive(Args)]
+pub struct MachineArgs {
+    /// Number of cells on the tape, accepts K, M and G suffixes
+    #[arg(long, value_name = "CELLS", default_value = "30000", value_parser = parse_size)]
+    pub tape_size: usize,
+
+    /// Whether the tape grows when the pointer moves past the end
+    #[arg(long, value_enum, default_value_t = TapeMode::Fixed)]
+    pub tape: TapeMode,
+
+    /// Width of a cell in bits: 8, 16, 32, 64 or unbounded
+    #[arg(
+        long,
+        value_name = "BITS",
+        default_value = "8",
+        value_parser = PossibleValuesParser::new(["8", "16", "32", "64", "unbounded"])
+            .map(|bits| bits.parse::<u32>().unwrap_or(UNBOUNDED_CELL_BITS)),
+    )]
+    pub cell_bits: u32,
+
+    /// What `+` and `-` do when a cell overflows
+    #[arg(long, value_enum, default_value_t = CellOverflow::Wrap)]
+    pub cell_overflow: CellOverflow,
+
+    /// Treat cells as signed, so they overflow at the signed limits
+    #[arg(long)]
+    pub cell_signed: bool,
+
+    /// What `,` stores at the end of input
+    #[arg(long, value_enum, default_value_t = EofMode::MinusOne)]
+    pub eof: EofMode,
+
+    /// How `,` reads its input
+    #[arg(long, value_enum, default_value_t = InputMode::Raw)]
+    pub input_mode: InputMode,
+}
+
+// The width of `--cell-bits unbounded`
+pub const UNBOUNDED_CELL_BITS: u32 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TapeMode {
+    /// Always --tape-size cells
+    Fixed,
+    /// Starts with --tape-size cells and grows to the right on demand
+    Growable,
+    /// --tape-size cells with cell 0 in the middle, so cells left of it
+    /// have negative indices
+    Bidirectional,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CellOverflow {
+    /// Wrap around to the other end
+    Wrap,
+    /// Stay at the largest or smallest value
+    Saturate,
+    /// Abort with the source position of the command
+    Trap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum InputMode {
+    /// Read every byte of the input in order
+    Raw,
+    /// Read the first character of a line and throw away the rest of it
+    Line,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EofMode {
+    /// Leave the cell as it is
+    Unchanged,
+    /// Set the cell to 0
+    Zero,
+    /// Set the cell to -1, all bits set
+    MinusOne,
+}
+
+fn parse_size(size: &str) -> Result<usize, String> {
+    let (digits, multiplier) = match size.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&size[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&size[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|cells| cells.checked_mul(multiplier))
+        .filter(|cells| *cells > 0)
+        .ok_or_else(|| format!("`{size}` isn't a positive number of cells"))
+}
+
+fn parse_symbol(symbol: &str) -> Result<String, String> {
+    let mut characters = symbol.chars();
+    let valid = characters
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && characters.all(|character| character.is_ascii_alphanumeric() || character == '_');
+
+    if valid {
+        Ok(symbol.to_string())
+    } else {
+        Err(format!("`{symbol}` isn't a valid symbol name"))
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run a program in the bytecode VM
+    ///
+    /// Exits with status 3 if one of the resource limits is exceeded.
+    Run(Box<RunArgs>),
+
+    /// Interactively run brainfuck against a persistent tape
+    Repl(ReplArgs),
+
+    /// Step through a program in an interactive debugger
+    Debug(DebugArgs),
+}
+
+#[derive(Args)]
+pub struct RunArgs {
+    /// Brainfuck source file or compiled bytecode (.bfc)
+    pub input_path: String,
+
+    /// Enable optimizations
+    #[arg(short = 'O')]
+    pub optimizations: bool,
+
+    #[command(flatten)]
+    pub machine: MachineArgs,
+
+    /// Stop after executing this many ops
+    #[arg(long, value_name = "STEPS")]
+    pub max_steps: Option<u64>,
+
+    /// Stop before writing more than this many bytes of output
+    #[arg(long, value_name = "BYTES")]
+    pub max_output_bytes: Option<u64>,
+
+    /// Stop after running for this many seconds
+    #[arg(long, value_name = "SECONDS")]
+    pub timeout: Option<f64>,
+
+    /// Stop when a cell past the first CELLS cells is accessed
+    #[arg(long, value_name = "CELLS")]
+    pub max_tape: Option<usize>,
+
+    /// Count how often every op runs and time every loop
+    #[arg(long)]
+    pub profile: bool,
+
+    /// How many loops and ops to list in the profile
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    pub profile_top: usize,
+
+    /// Write the source with execution counts in the margin, implies --profile
+    #[arg(long, value_name = "FILE")]
+    pub annotate: Option<String>,
+
+    /// Write which commands ran in lcov format, disables -O
+    #[arg(long, value_name = "FILE")]
+    pub coverage: Option<String>,
+
+    /// Write every executed op to a file
+    #[arg(long, value_name = "FILE")]
+    pub trace: Option<String>,
+
+    /// Format of the trace, chrome only records loops
+    #[arg(long, value_enum, default_value_t = TraceFormat::Jsonl)]
+    pub trace_format: TraceFormat,
+
+    /// Only trace every Nth step
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    pub trace_sample: u64,
+
+    /// Only trace ops in a source range, like 3:1-10:5 or 3-10
+    #[arg(long, value_name = "RANGE")]
+    pub trace_range: Option<String>,
+
+    /// Save every byte read by `,` to a file
+    #[arg(long, value_name = "FILE")]
+    pub record: Option<String>,
+
+    /// Read input from a file saved with --record instead of stdin
+    #[arg(long, value_name = "FILE")]
+    pub replay: Option<String>,
+
+    /// Start with the tape and pointer from a snapshot
+    #[arg(long, value_name = "FILE")]
+    pub load_tape: Option<String>,
+
+    /// Write a snapshot of the tape and pointer when the program finishes
+    #[arg(long, value_name = "FILE")]
+    pub save_tape: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TraceFormat {
+    /// One JSON object per step
+    Jsonl,
+    /// Chrome Trace Event format, for viewing loops as a flame chart
+    Chrome,
+}
+
+#[derive(Args)]
+pub struct ReplArgs {
+    /// Enable optimizations
+    #[arg(short = 'O')]
+    pub optimizations: bool,
+
+    #[command(flatten)]
+    pub machine: MachineArgs,
+}
+
+#[derive(Args)]
+pub struct DebugArgs {
+    /// Brainfuck source file
+    pub input_path: String,
+
+    /// Read the program's input from a file instead of stdin
+    #[arg(long, value_name = "FILE")]
+    pub input: Option<String>,
+
+    #[command(flatten)]
+    pub machine: MachineArgs,
 }
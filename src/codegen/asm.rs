@@ -1,18 +1,56 @@
-use super::Codegen;
-use crate::ast::AST;
+use super::{
+    baked_input_bytes, escape_string, fault_location, fault_locations, location_label,
+    snapshot_error, Codegen, CodegenOptions,
+};
+use crate::{
+    ast::AST,
+    bytecode::{cell_mask, cell_range},
+    cli::{CellOverflow, EofMode, InputMode, TapeMode},
+    span::Span,
+};
 
-static TEMPLATE_START: &'static str = include_str!("../../resources/asm/start.S");
-static TEMPLATE_END: &'static str = include_str!("../../resources/asm/end.S");
+static TEMPLATE_START: &str = include_str!("../../resources/asm/start.S");
+static TEMPLATE_END: &str = include_str!("../../resources/asm/end.S");
+static TEMPLATE_START_NO_LIBC: &str = include_str!("../../resources/asm/start_nolibc.S");
+static TEMPLATE_END_NO_LIBC: &str = include_str!("../../resources/asm/end_nolibc.S");
+static TEMPLATE_ADD_CELL: &str = include_str!("../../resources/asm/add_cell.S");
 
-static TEMPLATE_RIGHT: &'static str = include_str!("../../resources/asm/right.S");
-static TEMPLATE_LEFT: &'static str = include_str!("../../resources/asm/left.S");
-static TEMPLATE_ADD: &'static str = include_str!("../../resources/asm/add.S");
-static TEMPLATE_SUBTRACT: &'static str = include_str!("../../resources/asm/subtract.S");
+static TEMPLATE_RIGHT: &str = include_str!("../../resources/asm/right.S");
+static TEMPLATE_LEFT: &str = include_str!("../../resources/asm/left.S");
+static TEMPLATE_ADD: &str = include_str!("../../resources/asm/add.S");
+static TEMPLATE_SUBTRACT: &str = include_str!("../../resources/asm/subtract.S");
+static TEMPLATE_ADD_CHECKED: &str = include_str!("../../resources/asm/add_checked.S");
+static TEMPLATE_SUBTRACT_CHECKED: &str = include_str!("../../resources/asm/subtract_checked.S");
 
-static TEMPLATE_PRINT_CHAR: &'static str = include_str!("../../resources/asm/putchar.S");
-static TEMPLATE_GET_CHAR: &'static str = include_str!("../../resources/asm/getchar.S");
+static TEMPLATE_PRINT_CHAR: &str = include_str!("../../resources/asm/putchar.S");
+static TEMPLATE_GET_CHAR: &str = include_str!("../../resources/asm/getchar.S");
 
-static TEMPLATE_LOOP_END: &'static str = include_str!("../../resources/asm/loop.S");
+static TEMPLATE_BOUNDS_CHECK: &str = include_str!("../../resources/asm/check.S");
+static TEMPLATE_GROW_TAPE: &str = include_str!("../../resources/asm/grow.S");
+
+static TEMPLATE_LOOP_END: &str = include_str!("../../resources/asm/loop.S");
+
+static TEMPLATE_BAKED_INPUT: &str = include_str!("../../resources/asm/baked_input.S");
+
+// Parts of load_tape that depend on whether the tape grows
+static SNAPSHOT_SIZE_CHECK: &str = "        leaq    .SNAPSHOT_SIZE(%rip), %rdi
+        movq    %r13, %rdx
+        movq    tape_bytes(%rip), %rax
+        shrq    ${CELL_SHIFT}, %rax
+        cmpq    %rax, %r13
+        jne     .SNAPSHOT_FAILED";
+static SNAPSHOT_POINTER_CHECK: &str = "        cmpq    %r13, %r14
+        jae     .SNAPSHOT_FAILED";
+static SNAPSHOT_GROW: &str = "        leaq    1(%r14), %rsi
+        cmpq    %r13, %rsi
+    // cells for the snapshot and the pointer
+        cmovb   %r13, %rsi
+        shlq    ${CELL_SHIFT}, %rsi
+        cmpq    tape_bytes(%rip), %rsi
+        jbe     .SNAPSHOT_FITS
+        movq    memory(%rip), %rdi
+        call    grow_tape
+.SNAPSHOT_FITS:";
 
 use std::sync::atomic::{AtomicUsize, Ordering};
 
@@ -25,32 +63,45 @@ impl AssemblyCodeGenerator {
         let counter = GLOBAL_LABEL_COUNT.fetch_add(1, Ordering::SeqCst);
         format!("{hint}_{counter}")
     }
-    fn codegen_statments(statments: Vec<AST>, optimized: bool) -> String {
+    fn codegen_statments(statments: Vec<AST>, options: &CodegenOptions) -> String {
         statments
             .iter()
-            .map(|statment| Self::codegen_statment(statment, optimized) + "\n")
+            .map(|statment| Self::codegen_statment(statment, options) + "\n")
             .collect()
     }
 
-    fn codegen_statment(statment: &AST, optimized: bool) -> String {
-        match statment {
-            AST::Right(times) => Self::codegen_right(*times, optimized),
-            AST::Left(times) => Self::codegen_left(*times, optimized),
-            AST::Add(times) => Self::codegen_add(*times, optimized),
-            AST::Subtract(times) => Self::codegen_subtract(*times, optimized),
-            AST::PrintChar => Self::codegen_print_char().to_owned(),
-            AST::GetChar => Self::codegen_get_char().to_owned(),
-            AST::Loop(statments) => Self::codegen_loop(statments, optimized),
+    fn codegen_statment(statment: &AST, options: &CodegenOptions) -> String {
+        let label = match fault_location(statment) {
+            Some(position) if options.locations() && !matches!(statment, AST::Loop(..)) => {
+                format!(".{}:\n", location_label(position))
+            }
+            _ => String::new(),
+        };
+
+        let code = match statment {
+            AST::Right(times, _) => Self::codegen_right(*times, options),
+            AST::Left(times, _) => Self::codegen_left(*times, options),
+            AST::Add(times, span) => Self::codegen_add(*times, *span, options),
+            AST::Subtract(times, span) => Self::codegen_subtract(*times, *span, options),
+            AST::PrintChar(_) => Self::codegen_print_char().to_owned(),
+            AST::GetChar(_) => Self::codegen_get_char().to_owned(),
+            AST::Loop(statments, span) => Self::codegen_loop(statments, *span, options),
+            AST::BoundsCheck(span) => Self::codegen_bounds_check(*span, options),
+            AST::GrowTape(cells) => Self::codegen_grow_tape(*cells, options),
             _ => unreachable!(),
-        }
+        };
+
+        label + &code
     }
 
-    fn codegen_numeric(asm: &str, times: usize, optimized: bool) -> String {
-        if optimized {
-            asm.to_owned().replace("{TIMES}", &times.to_string())
+    // `unit` is what a single command adds, pointers move by whole cells
+    fn codegen_numeric(asm: &str, times: usize, unit: usize, options: &CodegenOptions) -> String {
+        if options.optimized {
+            asm.to_owned()
+                .replace("{TIMES}", &(times * unit).to_string())
         } else {
             let mut content = "".to_string();
-            let one = asm.to_owned().replace("{TIMES}", "1");
+            let one = asm.to_owned().replace("{TIMES}", &unit.to_string());
 
             for _ in 0..times {
                 content = format!("{content}\n{one}");
@@ -60,21 +111,65 @@ impl AssemblyCodeGenerator {
         }
     }
 
+    fn cell_bytes(options: &CodegenOptions) -> usize {
+        options.cell_bits as usize / 8
+    }
+
+    // Immediates have to fit in the cell
+    fn wrap(times: usize, options: &CodegenOptions) -> usize {
+        (times as u64 & cell_mask(options.cell_bits)) as usize
+    }
+
+    // Instruction suffix and the part of %rax that is as wide as a cell
+    fn cell_operands(options: &CodegenOptions) -> (&'static str, &'static str) {
+        match options.cell_bits {
+            8 => ("b", "%al"),
+            16 => ("w", "%ax"),
+            32 => ("l", "%eax"),
+            64 => ("q", "%rax"),
+            bits => panic!("Unsupported cell width {bits}"),
+        }
+    }
+
     // TODO: Loop over x times if optimization is disabled
-    fn codegen_right(times: usize, optimized: bool) -> String {
-        Self::codegen_numeric(TEMPLATE_RIGHT, times, optimized)
+    fn codegen_right(times: usize, options: &CodegenOptions) -> String {
+        Self::codegen_numeric(TEMPLATE_RIGHT, times, Self::cell_bytes(options), options)
     }
 
-    fn codegen_left(times: usize, optimized: bool) -> String {
-        Self::codegen_numeric(TEMPLATE_LEFT, times, optimized)
+    fn codegen_left(times: usize, options: &CodegenOptions) -> String {
+        Self::codegen_numeric(TEMPLATE_LEFT, times, Self::cell_bytes(options), options)
     }
 
-    fn codegen_add(times: usize, optimized: bool) -> String {
-        Self::codegen_numeric(TEMPLATE_ADD, times, optimized)
+    fn codegen_add(times: usize, span: Span, options: &CodegenOptions) -> String {
+        match options.cell_overflow {
+            CellOverflow::Wrap => {
+                Self::codegen_numeric(TEMPLATE_ADD, Self::wrap(times, options), 1, options)
+            }
+            _ => Self::codegen_checked(TEMPLATE_ADD_CHECKED, times, span, options),
+        }
     }
 
-    fn codegen_subtract(times: usize, optimized: bool) -> String {
-        Self::codegen_numeric(TEMPLATE_SUBTRACT, times, optimized)
+    fn codegen_subtract(times: usize, span: Span, options: &CodegenOptions) -> String {
+        match options.cell_overflow {
+            CellOverflow::Wrap => {
+                Self::codegen_numeric(TEMPLATE_SUBTRACT, Self::wrap(times, options), 1, options)
+            }
+            _ => Self::codegen_checked(TEMPLATE_SUBTRACT_CHECKED, times, span, options),
+        }
+    }
+
+    // `+` and `-` on cells that saturate or trap instead of wrapping, every
+    // copy of the command shares the location of the whole run
+    fn codegen_checked(asm: &str, times: usize, span: Span, options: &CodegenOptions) -> String {
+        let label = Self::gen_label("location");
+        let location = format!("{}:{span}", options.source_path);
+        let asm = asm.replace("{LABEL_LOCATION}", &label);
+
+        format!(
+            "{}\t.section .rodata\n.{label}:\n        .string \"{}\"\n\t.text\n",
+            Self::codegen_numeric(&asm, times, 1, options),
+            escape_string(&location)
+        )
     }
 
     fn codegen_print_char() -> &'static str {
@@ -85,17 +180,60 @@ impl AssemblyCodeGenerator {
         TEMPLATE_GET_CHAR
     }
 
-    fn codegen_loop(statments: &Vec<AST>, optimized: bool) -> String {
+    // Entries of the `locations` table the SIGSEGV handler looks through
+    fn codegen_locations(ast: &AST, options: &CodegenOptions) -> String {
+        let mut locations = vec![];
+        if let (true, AST::Root(statments)) = (options.locations(), ast) {
+            fault_locations(statments, &mut locations);
+        }
+
+        locations
+            .into_iter()
+            .map(|position| {
+                let label = location_label(position);
+                let location = format!("{}:{position}", options.source_path);
+
+                format!(
+                    "        .quad   .{label}, .{label}_location\n\t.section .rodata\n.{label}_location:\n        .string \"{}\"\n\t.data\n",
+                    escape_string(&location)
+                )
+            })
+            .collect()
+    }
+
+    fn codegen_bounds_check(span: Span, options: &CodegenOptions) -> String {
+        let location = format!("{}:{}", options.source_path, span.start);
+
+        TEMPLATE_BOUNDS_CHECK
+            .replace("{LABEL_IN_RANGE}", &Self::gen_label("in_range"))
+            .replace("{LABEL_LOCATION}", &Self::gen_label("location"))
+            .replace("{LOCATION}", &escape_string(&location))
+    }
+
+    // The tape has to reach the end of the cell `cells` right of the pointer
+    fn codegen_grow_tape(cells: usize, options: &CodegenOptions) -> String {
+        let bytes = (cells + 1) * Self::cell_bytes(options);
+
+        TEMPLATE_GROW_TAPE
+            .replace("{LABEL_ON_TAPE}", &Self::gen_label("on_tape"))
+            .replace("{BYTES}", &bytes.to_string())
+    }
+
+    fn codegen_loop(statments: &Vec<AST>, span: Span, options: &CodegenOptions) -> String {
         let body_label = Self::gen_label("loop_body");
         let condition_label = Self::gen_label("loop_condition");
 
         let mut content = format!("        jmp .{condition_label}\n.{body_label}:");
 
         for stmt in statments {
-            let generated = Self::codegen_statment(stmt, optimized);
+            let generated = Self::codegen_statment(stmt, options);
             content = format!("{content}\n{generated}");
         }
 
+        if options.locations() {
+            content = format!("{content}\n.{}:", location_label(span.end));
+        }
+
         let end = TEMPLATE_LOOP_END
             .to_string()
             .replace("{LABEL_COND}", &condition_label)
@@ -106,17 +244,164 @@ impl AssemblyCodeGenerator {
 }
 
 impl Codegen for AssemblyCodeGenerator {
-    fn codegen(ast: AST, optimized: bool) -> String {
-        let mut content = TEMPLATE_START.to_owned();
+    fn codegen(ast: AST, options: &CodegenOptions) -> String {
+        // Without libc the runtime is made of system calls
+        let (start, end) = if options.no_libc {
+            (TEMPLATE_START_NO_LIBC, TEMPLATE_END_NO_LIBC)
+        } else {
+            (TEMPLATE_START, TEMPLATE_END)
+        };
+
+        let mut content = start.to_owned();
+        let locations = Self::codegen_locations(&ast, options);
 
         match ast {
             AST::Root(statments) => {
-                let generated = Self::codegen_statments(statments, optimized);
+                let generated = Self::codegen_statments(statments, options);
                 content = format!("{content}\n{generated}");
             }
             _ => panic!("Expected AST::Root"),
         }
 
-        content + TEMPLATE_END
+        let (suffix, register) = Self::cell_operands(options);
+
+        // %rax is -1 at the end of input and %rbx the old value of the cell
+        let eof_action = match options.eof {
+            EofMode::Unchanged => "        movq    %rbx, %rax",
+            EofMode::Zero => "        movl    $0, %eax",
+            EofMode::MinusOne => "",
+        };
+
+        let (load_instruction, load_register) = match options.cell_bits {
+            8 => ("movzbq", "%rdi"),
+            16 => ("movzwq", "%rdi"),
+            32 => ("movl", "%edi"),
+            _ => ("movq", "%rdi"),
+        };
+
+        // Loads the cell at %rdi into %rax, sign extended if cells are signed
+        let load_value = match (options.cell_signed, options.cell_bits) {
+            (true, 8) => "movsbq  (%rdi), %rax",
+            (true, 16) => "movswq  (%rdi), %rax",
+            (true, 32) => "movslq  (%rdi), %rax",
+            (false, 8) => "movzbq  (%rdi), %rax",
+            (false, 16) => "movzwq  (%rdi), %rax",
+            (false, 32) => "movl    (%rdi), %eax",
+            _ => "movq    (%rdi), %rax",
+        };
+        let (cell_min, cell_max) = cell_range(options.cell_bits, options.cell_signed);
+
+        let overflow_action = match options.cell_overflow {
+            CellOverflow::Trap => "        jmp     .CELL_OVERFLOWED",
+            _ => "",
+        };
+
+        // Raw input skips throwing away the rest of the line
+        let raw_input = match options.input_mode {
+            InputMode::Raw => "        jmp     .RECORD",
+            InputMode::Line => "",
+        };
+
+        let tape_bytes = options.tape_size * Self::cell_bytes(options);
+
+        // The static tape is used unless the tape grows, has guard pages or
+        // is too large for it
+        let (tape_allocation, static_tape_bytes) = match options.tape {
+            TapeMode::Growable => ("        jmp     .MAPPED_TAPE", 1),
+            _ if options.guard_pages => ("", 1),
+            _ if options.mapped_tape() => ("        jmp     .MAPPED_TAPE", 1),
+            TapeMode::Fixed | TapeMode::Bidirectional => {
+                ("        jmp     .STATIC_TAPE", tape_bytes)
+            }
+        };
+
+        // Baked in input is read instead of calling getchar
+        let (read_byte, baked_input) = match &options.baked_input {
+            Some(input) => {
+                let bytes: String = baked_input_bytes(input)
+                    .iter()
+                    .map(|bytes| format!("        .byte   {bytes}\n"))
+                    .collect();
+
+                let baked_input = TEMPLATE_BAKED_INPUT
+                    .replace("{BAKED_INPUT_SIZE}", &input.len().to_string())
+                    .replace("{BAKED_INPUT_BYTES}\n", &bytes);
+
+                ("baked_byte", baked_input)
+            }
+            None if options.no_libc => ("read_byte", String::new()),
+            None => ("getchar", String::new()),
+        };
+
+        let reset_baked_input = match options.baked_input {
+            Some(_) => "        movq    $0, baked_input_position(%rip)",
+            None => "",
+        };
+
+        let write_byte = if options.no_libc {
+            "write_byte"
+        } else {
+            "putchar"
+        };
+
+        // A snapshot of a different size is rejected unless the tape grows,
+        // then the tape grows to fit the snapshot and the pointer. %r13 is the
+        // number of cells in the snapshot and %r14 the index of the pointer.
+        let (snapshot_size_check, snapshot_fit) = match options.tape {
+            TapeMode::Growable => ("", SNAPSHOT_GROW),
+            _ => (SNAPSHOT_SIZE_CHECK, SNAPSHOT_POINTER_CHECK),
+        };
+
+        let dump_tape = match options.dump_tape {
+            true => "",
+            false => "        jmp     .DUMP_INSTALLED",
+        };
+
+        (content + end)
+            .replace("{ADD_CELL}", TEMPLATE_ADD_CELL)
+            .replace("{MAIN}", &options.main_symbol)
+            .replace("{WRITE_BYTE}", write_byte)
+            .replace("{DUMP_TAPE}", dump_tape)
+            .replace("{DUMP_SIGNED}", if options.cell_signed { "1" } else { "0" })
+            .replace(
+                "{CELL_DIGITS}",
+                &(Self::cell_bytes(options) * 2).to_string(),
+            )
+            .replace("{SNAPSHOT_SIZE_CHECK}", snapshot_size_check)
+            .replace("{SNAPSHOT_FIT}", snapshot_fit)
+            .replace(
+                "{SNAPSHOT_ERROR}",
+                &escape_string(snapshot_error(options).unwrap_or_default()),
+            )
+            .replace("{CELL_BITS}", &options.cell_bits.to_string())
+            .replace("{RESET_BAKED_INPUT}", reset_baked_input)
+            .replace("{BAKED_INPUT}", &baked_input)
+            .replace("{READ_BYTE}", read_byte)
+            .replace("{LOCATIONS}", &locations)
+            .replace("{TAPE_ALLOCATION}", tape_allocation)
+            .replace("{STATIC_TAPE_BYTES}", &static_tape_bytes.to_string())
+            .replace("{TAPE_BYTES}", &tape_bytes.to_string())
+            .replace(
+                "{ORIGIN_BYTES}",
+                &(options.origin() * Self::cell_bytes(options)).to_string(),
+            )
+            .replace("{ORIGIN}", &options.origin().to_string())
+            .replace(
+                "{LOAD_CELL}",
+                &format!("{load_instruction:<8}(%rax), {load_register}"),
+            )
+            .replace(
+                "{CELL_SHIFT}",
+                &Self::cell_bytes(options).trailing_zeros().to_string(),
+            )
+            .replace("{EOF_ACTION}", eof_action)
+            .replace("{LOAD_CELL_VALUE}", load_value)
+            // As 64-bit two's complement, movabsq takes them either way
+            .replace("{CELL_MIN}", &(cell_min as i64).to_string())
+            .replace("{CELL_MAX}", &(cell_max as i64).to_string())
+            .replace("{OVERFLOW_ACTION}", overflow_action)
+            .replace("{RAW_INPUT}", raw_input)
+            .replace("{SUFFIX}", suffix)
+            .replace("{CELL_REGISTER}", register)
     }
 }
@@ -0,0 +1,142 @@
+// Bounds checks for the compiled backends. Instead of checking the pointer
+// after every move, a check is only inserted before a command that touches a
+// cell which isn't known to be on the tape yet. Within a block the offsets
+// from the pointer at the start of the block that have been checked form a
+// range, every cell between two checked cells is on the tape as well.
+
+use crate::{ast::AST, span::Span};
+
+pub fn insert_checks(ast: AST) -> AST {
+    let mut checker = Checker {
+        offset: 0,
+        checked: None,
+    };
+
+    match ast {
+        AST::Root(statments) => AST::Root(checker.statments(statments)),
+        _ => panic!("Expected AST::Root"),
+    }
+}
+
+struct Checker {
+    // Pointer movement since the start of the block
+    offset: i64,
+    // Offsets that are known to be on the tape
+    checked: Option<(i64, i64)>,
+}
+
+impl Checker {
+    fn statments(&mut self, statments: Vec<AST>) -> Vec<AST> {
+        let mut checked = vec![];
+
+        for statment in statments {
+            match statment {
+                AST::Right(times, _) => self.offset += times as i64,
+                AST::Left(times, _) => self.offset -= times as i64,
+                AST::Add(_, span)
+                | AST::Subtract(_, span)
+                | AST::PrintChar(span)
+                | AST::GetChar(span) => self.access(span, &mut checked),
+                AST::Loop(statments, span) => {
+                    let statment = self.checked_loop(statments, span, &mut checked);
+                    checked.push(statment);
+                    continue;
+                }
+                AST::Multiply(ref factors, span) => {
+                    // Reads the current cell and writes the cell at every offset
+                    self.access(span, &mut checked);
+                    for (offset, _) in factors {
+                        self.access_at(*offset, span, &mut checked);
+                    }
+                }
+                _ => {}
+            }
+
+            checked.push(statment);
+        }
+
+        checked
+    }
+
+    fn checked_loop(&mut self, statments: Vec<AST>, span: Span, checked: &mut Vec<AST>) -> AST {
+        // The condition reads the cell before the first iteration
+        self.access(Span::at(span.start), checked);
+
+        // Loops that end where they started don't move the pointer, so what is
+        // known before the loop still holds in and after it. Other loops start
+        // over from the cell the condition just checked.
+        let balanced = is_balanced(&statments);
+        let outer = (self.offset, self.checked);
+
+        if !balanced {
+            self.offset = 0;
+            self.checked = Some((0, 0));
+        }
+
+        let mut body = self.statments(statments);
+        // And after every iteration
+        self.access(Span::at(span.end), &mut body);
+
+        if balanced {
+            (self.offset, self.checked) = outer;
+        } else {
+            self.offset = 0;
+            self.checked = Some((0, 0));
+        }
+
+        AST::Loop(body, span)
+    }
+
+    fn access_at(&mut self, offset: i64, span: Span, checked: &mut Vec<AST>) {
+        let count = checked.len();
+
+        self.offset += offset;
+        self.access(span, checked);
+        self.offset -= offset;
+
+        if checked.len() > count {
+            let check = checked.pop().unwrap();
+            checked.extend([moved(offset, span), check, moved(-offset, span)]);
+        }
+    }
+
+    fn access(&mut self, span: Span, checked: &mut Vec<AST>) {
+        let offset = self.offset;
+
+        match self.checked {
+            Some((start, end)) if (start..=end).contains(&offset) => {}
+            Some((start, end)) => {
+                checked.push(AST::BoundsCheck(span));
+                self.checked = Some((start.min(offset), end.max(offset)));
+            }
+            None => {
+                checked.push(AST::BoundsCheck(span));
+                self.checked = Some((offset, offset));
+            }
+        }
+    }
+}
+
+// A check of the cell `offset` right of the pointer moves there and back
+fn moved(offset: i64, span: Span) -> AST {
+    if offset < 0 {
+        AST::Left(-offset as usize, span)
+    } else {
+        AST::Right(offset as usize, span)
+    }
+}
+
+fn is_balanced(statments: &[AST]) -> bool {
+    let mut offset = 0;
+
+    for statment in statments {
+        match statment {
+            AST::Right(times, _) => offset += *times as i64,
+            AST::Left(times, _) => offset -= *times as i64,
+            AST::Loop(statments, _) if !is_balanced(statments) => return false,
+            _ => {}
+        }
+    }
+
+    offset == 0
+}
@@ -1,44 +1,79 @@
-use super::Codegen;
-use crate::ast::AST;
+use super::{
+    baked_input_bytes, escape_string, fault_location, fault_locations, location_label,
+    snapshot_error, Codegen, CodegenOptions,
+};
+use crate::{
+    ast::AST,
+    cli::{CellOverflow, EofMode, InputMode, TapeMode, UNBOUNDED_CELL_BITS},
+    span::Span,
+};
 
-static TEMPLATE_START: &'static str = include_str!("../../resources/c/start.c");
-static TEMPLATE_END: &'static str = include_str!("../../resources/c/end.c");
+static TEMPLATE_START: &str = include_str!("../../resources/c/start.c");
+static TEMPLATE_END: &str = include_str!("../../resources/c/end.c");
 
-static TEMPLATE_RIGHT: &'static str = include_str!("../../resources/c/right.c");
-static TEMPLATE_LEFT: &'static str = include_str!("../../resources/c/left.c");
-static TEMPLATE_ADD: &'static str = include_str!("../../resources/c/add.c");
-static TEMPLATE_SUBTRACT: &'static str = include_str!("../../resources/c/subtract.c");
+static TEMPLATE_RIGHT: &str = include_str!("../../resources/c/right.c");
+static TEMPLATE_LEFT: &str = include_str!("../../resources/c/left.c");
+static TEMPLATE_ADD: &str = include_str!("../../resources/c/add.c");
+static TEMPLATE_SUBTRACT: &str = include_str!("../../resources/c/subtract.c");
+static TEMPLATE_ADD_CHECKED: &str = include_str!("../../resources/c/add_checked.c");
+static TEMPLATE_SUBTRACT_CHECKED: &str = include_str!("../../resources/c/subtract_checked.c");
 
-static TEMPLATE_PRINT_CHAR: &'static str = include_str!("../../resources/c/putchar.c");
-static TEMPLATE_GET_CHAR: &'static str = include_str!("../../resources/c/getchar.c");
+static TEMPLATE_PRINT_CHAR: &str = include_str!("../../resources/c/putchar.c");
+static TEMPLATE_GET_CHAR: &str = include_str!("../../resources/c/getchar.c");
 
-static TEMPLATE_LOOP: &'static str = include_str!("../../resources/c/loop.c");
+static TEMPLATE_BOUNDS_CHECK: &str = include_str!("../../resources/c/check.c");
+static TEMPLATE_GROW_TAPE: &str = include_str!("../../resources/c/grow.c");
+
+static TEMPLATE_LOOP: &str = include_str!("../../resources/c/loop.c");
+
+static TEMPLATE_UNBOUNDED_RUNTIME: &str = include_str!("../../resources/c/unbounded.c");
+static TEMPLATE_ADD_UNBOUNDED: &str = include_str!("../../resources/c/add_unbounded.c");
+static TEMPLATE_SUBTRACT_UNBOUNDED: &str = include_str!("../../resources/c/subtract_unbounded.c");
+static TEMPLATE_PRINT_CHAR_UNBOUNDED: &str = include_str!("../../resources/c/putchar_unbounded.c");
+static TEMPLATE_GET_CHAR_UNBOUNDED: &str = include_str!("../../resources/c/getchar_unbounded.c");
+static TEMPLATE_BOUNDS_CHECK_UNBOUNDED: &str = include_str!("../../resources/c/check_unbounded.c");
+static TEMPLATE_LOOP_UNBOUNDED: &str = include_str!("../../resources/c/loop_unbounded.c");
+static TEMPLATE_MULTIPLY: &str = include_str!("../../resources/c/multiply.c");
+static TEMPLATE_CLEAR: &str = include_str!("../../resources/c/clear.c");
+static TEMPLATE_MULTIPLY_GUARD: &str = include_str!("../../resources/c/multiply_guard.c");
 
 pub struct CCodeGenerator {}
 
 impl CCodeGenerator {
-    fn codegen_statments(statments: Vec<AST>, optimized: bool) -> String {
+    fn codegen_statments(statments: Vec<AST>, options: &CodegenOptions) -> String {
         statments
             .iter()
-            .map(|statment| Self::codegen_statment(statment, optimized) + "\n")
+            .map(|statment| Self::codegen_statment(statment, options) + "\n")
             .collect()
     }
 
-    fn codegen_statment(statment: &AST, optimized: bool) -> String {
-        match statment {
-            AST::Right(times) => Self::codegen_right(*times, optimized),
-            AST::Left(times) => Self::codegen_left(*times, optimized),
-            AST::Add(times) => Self::codegen_add(*times, optimized),
-            AST::Subtract(times) => Self::codegen_subtract(*times, optimized),
-            AST::PrintChar => Self::codegen_print_char().to_owned(),
-            AST::GetChar => Self::codegen_get_char().to_owned(),
-            AST::Loop(statments) => Self::codegen_loop(statments, optimized),
+    fn codegen_statment(statment: &AST, options: &CodegenOptions) -> String {
+        let label = match fault_location(statment) {
+            Some(position) if options.locations() && !matches!(statment, AST::Loop(..)) => {
+                format!("{}:;\n", location_label(position))
+            }
+            _ => String::new(),
+        };
+
+        let code = match statment {
+            AST::Right(times, _) => Self::codegen_right(*times, options),
+            AST::Left(times, _) => Self::codegen_left(*times, options),
+            AST::Add(times, span) => Self::codegen_add(*times, *span, options),
+            AST::Subtract(times, span) => Self::codegen_subtract(*times, *span, options),
+            AST::PrintChar(_) => Self::codegen_print_char(options).to_owned(),
+            AST::GetChar(_) => Self::codegen_get_char(options).to_owned(),
+            AST::Loop(statments, span) => Self::codegen_loop(statments, *span, options),
+            AST::BoundsCheck(span) => Self::codegen_bounds_check(*span, options),
+            AST::GrowTape(cells) => Self::codegen_grow_tape(*cells),
+            AST::Multiply(factors, _) => Self::codegen_multiply(factors),
             _ => unreachable!(),
-        }
+        };
+
+        label + &code
     }
 
-    fn codegen_numeric(c: &str, times: usize, optimized: bool) -> String {
-        if optimized {
+    fn codegen_numeric(c: &str, times: usize, options: &CodegenOptions) -> String {
+        if options.optimized {
             c.to_owned().replace("{TIMES}", &times.to_string())
         } else {
             let mut content = "".to_string();
@@ -53,54 +88,250 @@ impl CCodeGenerator {
     }
 
     // TODO: Loop over x times if optimization is disabled
-    fn codegen_right(times: usize, optimized: bool) -> String {
-        Self::codegen_numeric(TEMPLATE_RIGHT, times, optimized)
+    fn codegen_right(times: usize, options: &CodegenOptions) -> String {
+        Self::codegen_numeric(TEMPLATE_RIGHT, times, options)
+    }
+
+    fn codegen_left(times: usize, options: &CodegenOptions) -> String {
+        Self::codegen_numeric(TEMPLATE_LEFT, times, options)
+    }
+
+    fn codegen_add(times: usize, span: Span, options: &CodegenOptions) -> String {
+        if options.unbounded() {
+            return Self::codegen_numeric(TEMPLATE_ADD_UNBOUNDED, times, options);
+        }
+
+        match options.cell_overflow {
+            CellOverflow::Wrap => Self::codegen_numeric(TEMPLATE_ADD, times, options),
+            _ => Self::codegen_checked(TEMPLATE_ADD_CHECKED, times, span, options),
+        }
+    }
+
+    fn codegen_subtract(times: usize, span: Span, options: &CodegenOptions) -> String {
+        if options.unbounded() {
+            return Self::codegen_numeric(TEMPLATE_SUBTRACT_UNBOUNDED, times, options);
+        }
+
+        match options.cell_overflow {
+            CellOverflow::Wrap => Self::codegen_numeric(TEMPLATE_SUBTRACT, times, options),
+            _ => Self::codegen_checked(TEMPLATE_SUBTRACT_CHECKED, times, span, options),
+        }
+    }
+
+    // `+` and `-` on cells that saturate or trap instead of wrapping, the
+    // location is the whole run they're in
+    fn codegen_checked(c: &str, times: usize, span: Span, options: &CodegenOptions) -> String {
+        let location = format!("{}:{span}", options.source_path);
+        let c = c.replace("{LOCATION}", &escape_string(&location));
+
+        Self::codegen_numeric(&c, times, options)
+    }
+
+    fn codegen_print_char(options: &CodegenOptions) -> &'static str {
+        if options.unbounded() {
+            TEMPLATE_PRINT_CHAR_UNBOUNDED
+        } else {
+            TEMPLATE_PRINT_CHAR
+        }
     }
 
-    fn codegen_left(times: usize, optimized: bool) -> String {
-        Self::codegen_numeric(TEMPLATE_LEFT, times, optimized)
+    fn codegen_get_char(options: &CodegenOptions) -> &'static str {
+        if options.unbounded() {
+            TEMPLATE_GET_CHAR_UNBOUNDED
+        } else {
+            TEMPLATE_GET_CHAR
+        }
     }
 
-    fn codegen_add(times: usize, optimized: bool) -> String {
-        Self::codegen_numeric(TEMPLATE_ADD, times, optimized)
+    // Fills the `locations` table the SIGSEGV handler looks through, it has to
+    // be in `main` to take the address of the labels
+    fn codegen_locations(ast: &AST, options: &CodegenOptions) -> String {
+        let mut locations = vec![];
+        match (options.locations(), ast) {
+            (true, AST::Root(statments)) => fault_locations(statments, &mut locations),
+            _ => return String::new(),
+        }
+
+        let entries: String = locations
+            .into_iter()
+            .map(|position| {
+                let location = format!("{}:{position}", options.source_path);
+                format!(
+                    "    {{&&{}, \"{}\"}},\n",
+                    location_label(position),
+                    escape_string(&location)
+                )
+            })
+            .collect();
+
+        format!(
+            "  static struct location table[] = {{\n{entries}    {{&&program_end, NULL}},\n  }};\n  locations = table;\n  location_count = sizeof(table) / sizeof(*table);\n\n  // Jumping to a label keeps the optimizer from removing them\n  static volatile int never = 0;\n  if (never)\n    goto *table[never].address;"
+        )
     }
 
-    fn codegen_subtract(times: usize, optimized: bool) -> String {
-        Self::codegen_numeric(TEMPLATE_SUBTRACT, times, optimized)
+    fn codegen_bounds_check(span: Span, options: &CodegenOptions) -> String {
+        let location = format!("{}:{}", options.source_path, span.start);
+        let template = if options.unbounded() {
+            TEMPLATE_BOUNDS_CHECK_UNBOUNDED
+        } else {
+            TEMPLATE_BOUNDS_CHECK
+        };
+
+        template.replace("{LOCATION}", &escape_string(&location))
     }
 
-    fn codegen_print_char() -> &'static str {
-        TEMPLATE_PRINT_CHAR
+    fn codegen_grow_tape(cells: usize) -> String {
+        TEMPLATE_GROW_TAPE.replace("{CELLS}", &cells.to_string())
     }
 
-    fn codegen_get_char() -> &'static str {
-        TEMPLATE_GET_CHAR
+    fn codegen_multiply(factors: &[(i64, i64)]) -> String {
+        let mut content: String = factors
+            .iter()
+            .map(|(offset, factor)| {
+                TEMPLATE_MULTIPLY
+                    .replace("{OFFSET}", &offset.to_string())
+                    .replace("{FACTOR}", &factor.to_string())
+            })
+            .collect();
+
+        content.push_str(TEMPLATE_CLEAR);
+        TEMPLATE_MULTIPLY_GUARD.replace("{ BODY }", &content)
     }
 
-    fn codegen_loop(statments: &Vec<AST>, optimized: bool) -> String {
+    fn codegen_loop(statments: &Vec<AST>, span: Span, options: &CodegenOptions) -> String {
         let mut body = "".to_string();
 
         for stmt in statments {
-            let generated = Self::codegen_statment(stmt, optimized);
+            let generated = Self::codegen_statment(stmt, options);
             body = format!("{body}\n{generated}");
         }
 
-        TEMPLATE_LOOP.to_string().replace("{ BODY }", &body)
+        if options.locations() {
+            body = format!("{body}\n{}:;", location_label(span.end));
+        }
+
+        let template = if options.unbounded() {
+            TEMPLATE_LOOP_UNBOUNDED
+        } else {
+            TEMPLATE_LOOP
+        };
+
+        template.to_string().replace("{ BODY }", &body)
     }
 }
 
 impl Codegen for CCodeGenerator {
-    fn codegen(ast: AST, optimized: bool) -> String {
+    fn codegen(ast: AST, options: &CodegenOptions) -> String {
         let mut content = TEMPLATE_START.to_owned();
+        let locations = Self::codegen_locations(&ast, options);
 
         match ast {
             AST::Root(statments) => {
-                let generated = Self::codegen_statments(statments, optimized);
+                let generated = Self::codegen_statments(statments, options);
                 content = format!("{content}\n{generated}");
             }
             _ => panic!("Expected AST::Root"),
         }
 
-        content + TEMPLATE_END
+        let cell = match options.cell_bits {
+            8 => "uint8_t",
+            16 => "uint16_t",
+            32 => "uint32_t",
+            64 => "uint64_t",
+            UNBOUNDED_CELL_BITS => "big",
+            bits => panic!("Unsupported cell width {bits}"),
+        };
+
+        // int8_t instead of uint8_t and so on
+        let arithmetic_cell = if options.cell_signed {
+            &cell[1..]
+        } else {
+            cell
+        };
+
+        let (cell_min, cell_max) = match (options.cell_signed, options.cell_bits) {
+            (_, UNBOUNDED_CELL_BITS) => ("0".to_string(), "0".to_string()),
+            (true, bits) => (format!("INT{bits}_MIN"), format!("INT{bits}_MAX")),
+            (false, bits) => ("0".to_string(), format!("UINT{bits}_MAX")),
+        };
+
+        let cell_overflow = match options.cell_overflow {
+            CellOverflow::Wrap => "OVERFLOW_WRAP",
+            CellOverflow::Saturate => "OVERFLOW_SATURATE",
+            CellOverflow::Trap => "OVERFLOW_TRAP",
+        };
+
+        let eof = match options.eof {
+            EofMode::Unchanged => "EOF_UNCHANGED",
+            EofMode::Zero => "EOF_ZERO",
+            EofMode::MinusOne => "EOF_MINUS_ONE",
+        };
+
+        let baked_input = options.baked_input.as_deref().unwrap_or_default();
+        let baked_input_bytes = match baked_input {
+            [] => "0".to_string(),
+            input => baked_input_bytes(input).join(",\n  "),
+        };
+
+        // First, the runtime has placeholders of its own
+        let unbounded_runtime = if options.unbounded() {
+            TEMPLATE_UNBOUNDED_RUNTIME
+        } else {
+            ""
+        };
+
+        let snapshot_error = match snapshot_error(options) {
+            Some(error) => format!("\"{}\"", escape_string(error)),
+            None => "NULL".to_string(),
+        };
+
+        (content + TEMPLATE_END)
+            .replace("{UNBOUNDED_RUNTIME}", unbounded_runtime)
+            .replace("{SNAPSHOT_ERROR}", &snapshot_error)
+            .replace(
+                "{UNBOUNDED_CELLS}",
+                if options.unbounded() { "1" } else { "0" },
+            )
+            .replace("{LOCATIONS}", &locations)
+            .replace(
+                "{BAKED_INPUT}",
+                if options.baked_input.is_some() {
+                    "1"
+                } else {
+                    "0"
+                },
+            )
+            .replace("{BAKED_INPUT_BYTES}", &baked_input_bytes)
+            .replace("{BAKED_INPUT_SIZE}", &baked_input.len().to_string())
+            .replace(
+                "{MAPPED_TAPE}",
+                if options.mapped_tape() { "1" } else { "0" },
+            )
+            .replace("{GUARD_PAGES}", if options.guard_pages { "1" } else { "0" })
+            .replace("{DUMP_TAPE}", if options.dump_tape { "1" } else { "0" })
+            .replace(
+                "{GROWABLE_TAPE}",
+                if options.tape == TapeMode::Growable {
+                    "1"
+                } else {
+                    "0"
+                },
+            )
+            .replace("{TAPE_SIZE}", &options.tape_size.to_string())
+            .replace("{ORIGIN}", &options.origin().to_string())
+            .replace("{ARITHMETIC_CELL}", arithmetic_cell)
+            .replace("{CELL_MIN}", &cell_min)
+            .replace("{CELL_MAX}", &cell_max)
+            .replace("{CELL_OVERFLOW}", cell_overflow)
+            .replace("{CELL}", cell)
+            .replace("{EOF_MODE}", eof)
+            .replace(
+                "{LINE_INPUT}",
+                if options.input_mode == InputMode::Line {
+                    "1"
+                } else {
+                    "0"
+                },
+            )
     }
 }
@@ -0,0 +1,75 @@
+// Compile-time evaluation of programs with baked in input. Such a program does
+// the same thing every time it runs, so with -O it is run in the VM right
+// away, and if it finishes it is replaced by one that only writes what it
+// wrote. Programs that fail, run too long or write too much are compiled as
+// they are, and report the failure when they run. So are programs that dump
+// their tape, which an evaluated program doesn't have.
+
+use super::CodegenOptions;
+use crate::{
+    ast::AST,
+    bytecode::{self, Config, Limits, Vm},
+    span::{Position, Span},
+};
+
+const MAX_STEPS: u64 = 100_000_000;
+const MAX_OUTPUT_BYTES: u64 = 1 << 20;
+
+pub fn evaluate(ast: &AST, options: &CodegenOptions) -> Option<AST> {
+    let input = options
+        .baked_input
+        .as_deref()
+        .filter(|_| options.optimized && !options.dump_tape)?;
+
+    let config = Config {
+        tape_size: options.tape_size,
+        tape: options.tape,
+        cell_bits: options.cell_bits,
+        cell_overflow: options.cell_overflow,
+        cell_signed: options.cell_signed,
+        eof: options.eof,
+        input_mode: options.input_mode,
+    };
+    let limits = Limits {
+        max_steps: Some(MAX_STEPS),
+        max_output_bytes: Some(MAX_OUTPUT_BYTES),
+        ..Limits::default()
+    };
+
+    let program = bytecode::compile(ast, options);
+    let mut input = input;
+    let mut output = vec![];
+    Vm::new(&program, config, limits, &mut input, &mut output)
+        .run()
+        .ok()?;
+
+    Some(AST::Root(writes(&output, options)))
+}
+
+// Writes every byte from cell 0, by adding the difference to the last one
+fn writes(output: &[u8], options: &CodegenOptions) -> Vec<AST> {
+    let span = Span::at(Position::new(1, 1));
+    let mut statments = vec![];
+    let mut cell = 0;
+
+    for byte in output {
+        // `.` only writes the low byte, so bytes above 127 are negative in
+        // signed 8-bit cells to keep them from overflowing
+        let value = if options.cell_signed && options.cell_bits == 8 {
+            *byte as i8 as i64
+        } else {
+            *byte as i64
+        };
+
+        match value - cell {
+            0 => {}
+            difference if difference > 0 => statments.push(AST::Add(difference as usize, span)),
+            difference => statments.push(AST::Subtract(-difference as usize, span)),
+        }
+
+        statments.push(AST::PrintChar(span));
+        cell = value;
+    }
+
+    statments
+}
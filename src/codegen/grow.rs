@@ -0,0 +1,70 @@
+// Growth checks for growable tapes. The statments between two loops run
+// straight through, so a single check in front of them grows the tape far
+// enough for the furthest cell right of the pointer they touch. Every run
+// starts on a cell that is already on the tape: the first one, or the one the
+// condition of a loop just read.
+
+use crate::ast::AST;
+
+pub fn insert_growth(ast: AST) -> AST {
+    match ast {
+        AST::Root(statments) => AST::Root(block(statments, false)),
+        _ => panic!("Expected AST::Root"),
+    }
+}
+
+#[derive(Default)]
+struct Run {
+    statments: Vec<AST>,
+    // Pointer movement since the start of the run
+    offset: i64,
+    // Furthest cell right of the start that is touched
+    furthest: i64,
+}
+
+impl Run {
+    fn access(&mut self) {
+        self.furthest = self.furthest.max(self.offset);
+    }
+
+    fn finish(self, grown: &mut Vec<AST>) {
+        if self.furthest > 0 {
+            grown.push(AST::GrowTape(self.furthest as usize));
+        }
+
+        grown.extend(self.statments);
+    }
+}
+
+// The condition at the end of a loop body belongs to the last run
+fn block(statments: Vec<AST>, loop_body: bool) -> Vec<AST> {
+    let mut grown = vec![];
+    let mut run = Run::default();
+
+    for statment in statments {
+        match statment {
+            AST::Right(times, _) => run.offset += times as i64,
+            AST::Left(times, _) => run.offset -= times as i64,
+            AST::Add(..) | AST::Subtract(..) | AST::PrintChar(_) | AST::GetChar(_) => run.access(),
+            AST::Loop(statments, span) => {
+                // The condition reads the cell before the first iteration
+                run.access();
+                std::mem::take(&mut run).finish(&mut grown);
+
+                let body = block(statments, true);
+                grown.push(AST::Loop(body, span));
+                continue;
+            }
+            _ => {}
+        }
+
+        run.statments.push(statment);
+    }
+
+    if loop_body {
+        run.access();
+    }
+    run.finish(&mut grown);
+
+    grown
+}
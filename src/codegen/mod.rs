@@ -1,15 +1,175 @@
-use crate::ast::AST;
+use crate::{
+    ast::AST,
+    cli::{CellOverflow, EofMode, InputMode, TapeMode, UNBOUNDED_CELL_BITS},
+    span::Position,
+};
 
 pub mod asm;
+mod bounds;
 pub mod c;
+mod evaluate;
+mod grow;
+mod multiply;
 
 pub use asm::AssemblyCodeGenerator;
 pub use c::CCodeGenerator;
 
+// Larger tapes are mapped when the program starts instead of being static
+// arrays, code can only reach the 2 GiB around it
+const MAX_STATIC_TAPE_BYTES: usize = 1 << 30;
+
+// Everything besides the program that changes the generated code
+#[derive(Debug, Clone)]
+pub struct CodegenOptions {
+    pub optimized: bool,
+    pub tape_size: usize,
+    pub tape: TapeMode,
+    pub cell_bits: u32,
+    pub cell_overflow: CellOverflow,
+    pub cell_signed: bool,
+    pub eof: EofMode,
+    pub input_mode: InputMode,
+    pub bounds_check: bool,
+    pub guard_pages: bool,
+    pub dump_tape: bool,
+    // Use system calls instead of libc, only the assembly backend can
+    pub no_libc: bool,
+    // The function the program is, `main` unless it goes in an object file
+    pub main_symbol: String,
+    // Read instead of stdin by the compiled program
+    pub baked_input: Option<Vec<u8>>,
+    // Set by `codegen` when the program was run at compile time
+    pub evaluated: bool,
+    // Used in error messages of the compiled program
+    pub source_path: String,
+}
+
+impl CodegenOptions {
+    // Where cell 0 is on the tape, the middle of a bidirectional tape
+    pub fn origin(&self) -> usize {
+        match self.tape {
+            TapeMode::Bidirectional => self.tape_size / 2,
+            _ => 0,
+        }
+    }
+
+    // Whether a fixed or bidirectional tape is too large to be a static array.
+    // Unbounded cells are allocated a page at a time anyway.
+    pub fn mapped_tape(&self) -> bool {
+        !self.unbounded()
+            && self.tape_size.saturating_mul(self.cell_bits as usize / 8) > MAX_STATIC_TAPE_BYTES
+    }
+
+    pub fn unbounded(&self) -> bool {
+        self.cell_bits == UNBOUNDED_CELL_BITS
+    }
+
+    // Whether commands get labels to find the one that faulted or was
+    // interrupted
+    pub fn locations(&self) -> bool {
+        self.guard_pages || self.dump_tape
+    }
+}
+
 pub trait Codegen {
-    fn codegen(ast: AST, optimized: bool) -> String;
+    fn codegen(ast: AST, options: &CodegenOptions) -> String;
+}
+
+pub fn codegen<T: Codegen>(ast: AST, options: &CodegenOptions) -> String {
+    if let Some(ast) = evaluate::evaluate(&ast, options) {
+        // The program only writes to cell 0, it needs no checks
+        let options = CodegenOptions {
+            bounds_check: false,
+            guard_pages: false,
+            evaluated: true,
+            ..options.clone()
+        };
+
+        return T::codegen(ast, &options);
+    }
+
+    let ast = if options.unbounded() && options.optimized {
+        multiply::insert_multiplications(ast)
+    } else {
+        ast
+    };
+
+    let ast = if options.bounds_check {
+        bounds::insert_checks(ast)
+    } else {
+        ast
+    };
+
+    // The sparse tape of unbounded cells grows by itself
+    let ast = if options.tape == TapeMode::Growable && !options.unbounded() {
+        grow::insert_growth(ast)
+    } else {
+        ast
+    };
+
+    T::codegen(ast, options)
+}
+
+// Escapes text for string literals in C and assembly
+fn escape_string(text: &str) -> String {
+    text.chars()
+        .map(|character| match character {
+            '"' => "\\\"".to_string(),
+            '\\' => "\\\\".to_string(),
+            '\n' => "\\n".to_string(),
+            _ => character.to_string(),
+        })
+        .collect()
+}
+
+// With guard pages every command that touches a cell gets a label in front of
+// its code, as does the end of every loop where the condition is checked
+fn fault_location(statment: &AST) -> Option<Position> {
+    match statment {
+        AST::Add(_, span) | AST::Subtract(_, span) | AST::PrintChar(span) | AST::GetChar(span) => {
+            Some(span.start)
+        }
+        AST::Loop(_, span) => Some(span.end),
+        _ => None,
+    }
+}
+
+fn fault_locations(statments: &[AST], locations: &mut Vec<Position>) {
+    for statment in statments {
+        if let AST::Loop(statments, _) = statment {
+            fault_locations(statments, locations);
+        }
+
+        locations.extend(fault_location(statment));
+    }
+}
+
+// Why the compiled program can't load or save its tape with --load-tape and
+// --save-tape, if it can't
+fn snapshot_error(options: &CodegenOptions) -> Option<&'static str> {
+    if options.unbounded() {
+        Some("Tape snapshots don't support unbounded cells")
+    } else if options.evaluated {
+        Some("The tape of a program evaluated at compile time can't be loaded or saved, compile it without -O")
+    } else {
+        None
+    }
+}
+
+// The baked in input as the elements of an array or list of bytes
+fn baked_input_bytes(input: &[u8]) -> Vec<String> {
+    input
+        .chunks(16)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .map(|byte| byte.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .collect()
 }
 
-pub fn codegen<T: Codegen>(ast: AST, optimized: bool) -> String {
-    T::codegen(ast, optimized)
+fn location_label(position: Position) -> String {
+    format!("op_{}_{}", position.line, position.column)
 }
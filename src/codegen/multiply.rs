@@ -0,0 +1,60 @@
+// Multiply loops for unbounded cells. With fixed widths the C compiler turns
+// loops like `[->++<]` into a multiplication on its own, but it can't see
+// through the calls into the bignum runtime. Only loops that count the
+// current cell down by one are replaced. They never end on a negative cell,
+// so the multiplication only happens on one that isn't and the loop is kept
+// after it, where it only runs on a negative cell.
+
+use std::collections::BTreeMap;
+
+use crate::{ast::AST, span::Span};
+
+pub fn insert_multiplications(ast: AST) -> AST {
+    match ast {
+        AST::Root(statments) => AST::Root(block(statments)),
+        _ => panic!("Expected AST::Root"),
+    }
+}
+
+fn block(statments: Vec<AST>) -> Vec<AST> {
+    statments
+        .into_iter()
+        .flat_map(|statment| match statment {
+            AST::Loop(statments, span) => {
+                let multiplication = multiplication(&statments, span);
+                multiplication
+                    .into_iter()
+                    .chain([AST::Loop(block(statments), span)])
+                    .collect()
+            }
+            statment => vec![statment],
+        })
+        .collect()
+}
+
+fn multiplication(statments: &[AST], span: Span) -> Option<AST> {
+    let mut offset = 0;
+    let mut deltas: BTreeMap<i64, i64> = BTreeMap::new();
+
+    for statment in statments {
+        match statment {
+            AST::Right(times, _) => offset += *times as i64,
+            AST::Left(times, _) => offset -= *times as i64,
+            AST::Add(times, _) => *deltas.entry(offset).or_default() += *times as i64,
+            AST::Subtract(times, _) => *deltas.entry(offset).or_default() -= *times as i64,
+            _ => return None,
+        }
+    }
+
+    if offset != 0 {
+        return None;
+    }
+
+    let counter = deltas.remove(&0).unwrap_or(0);
+    let factors: Vec<_> = deltas
+        .into_iter()
+        .filter(|(_, delta)| *delta != 0)
+        .collect();
+
+    (counter == -1).then_some(AST::Multiply(factors, span))
+}
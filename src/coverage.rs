@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+
+use crate::bytecode::{Observer, Op, Program, Vm};
+
+pub struct Coverage<'p> {
+    program: &'p Program,
+    counts: Vec<u64>,
+    // How often every `JumpIfZero` entered and skipped its loop
+    entered: Vec<u64>,
+    skipped: Vec<u64>,
+}
+
+impl Observer for Coverage<'_> {
+    fn before(&mut self, _vm: &Vm, pc: usize) {
+        self.counts[pc] += 1;
+    }
+
+    fn after(&mut self, vm: &Vm, pc: usize) {
+        if let Op::JumpIfZero(_) = self.program.ops[pc] {
+            if vm.pc() == pc + 1 {
+                self.entered[pc] += 1;
+            } else {
+                self.skipped[pc] += 1;
+            }
+        }
+    }
+}
+
+impl<'p> Coverage<'p> {
+    pub fn new(program: &'p Program) -> Self {
+        Self {
+            program,
+            counts: vec![0; program.ops.len()],
+            entered: vec![0; program.ops.len()],
+            skipped: vec![0; program.ops.len()],
+        }
+    }
+
+    // A line is hit as often as the most executed op on it. Every loop is a
+    // branch with two outcomes: entering the body and skipping it.
+    pub fn lcov(&self, source_path: &str, source: &str) -> String {
+        let lines: Vec<&str> = source.lines().collect();
+        let mut hits: BTreeMap<usize, u64> = BTreeMap::new();
+
+        for (span, count) in self.program.spans.iter().zip(&self.counts) {
+            for line in span.start.line..=span.end.line {
+                let has_commands = lines
+                    .get(line - 1)
+                    .is_some_and(|text| text.chars().any(|c| "><+-.,[]".contains(c)));
+
+                if has_commands {
+                    let hit = hits.entry(line).or_default();
+                    *hit = (*hit).max(*count);
+                }
+            }
+        }
+
+        let mut lcov = format!("TN:\nSF:{source_path}\n");
+
+        let mut branches_found = 0;
+        let mut branches_hit = 0;
+        let loops = (0..self.program.ops.len())
+            .filter(|pc| matches!(self.program.ops[*pc], Op::JumpIfZero(_)));
+
+        for (block, pc) in loops.enumerate() {
+            let line = self.program.spans[pc].start.line;
+
+            for (branch, taken) in [self.entered[pc], self.skipped[pc]].into_iter().enumerate() {
+                let taken = if self.counts[pc] == 0 {
+                    "-".to_string()
+                } else {
+                    taken.to_string()
+                };
+
+                lcov += &format!("BRDA:{line},{block},{branch},{taken}\n");
+            }
+
+            branches_found += 2;
+            branches_hit += (self.entered[pc] > 0) as usize + (self.skipped[pc] > 0) as usize;
+        }
+        lcov += &format!("BRF:{branches_found}\nBRH:{branches_hit}\n");
+
+        for (line, count) in &hits {
+            lcov += &format!("DA:{line},{count}\n");
+        }
+
+        let lines_hit = hits.values().filter(|count| **count > 0).count();
+        lcov += &format!("LF:{}\nLH:{lines_hit}\nend_of_record\n", hits.len());
+
+        lcov
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::{compile_source, Limits};
+
+    #[test]
+    fn lcov() {
+        let source = "+[-]\n[\n[>]\n]\n.\n; comment\n";
+        let (program, config) = compile_source(source, &[]);
+        let mut input: &[u8] = b"";
+        let mut output = vec![];
+
+        let mut coverage = Coverage::new(&program);
+        Vm::new(&program, config, Limits::default(), &mut input, &mut output)
+            .run_with(&mut [&mut coverage])
+            .unwrap();
+
+        assert_eq!(
+            coverage.lcov("test.bf", source),
+            "TN:\n\
+             SF:test.bf\n\
+             BRDA:1,0,0,1\n\
+             BRDA:1,0,1,0\n\
+             BRDA:2,1,0,0\n\
+             BRDA:2,1,1,1\n\
+             BRDA:3,2,0,-\n\
+             BRDA:3,2,1,-\n\
+             BRF:6\n\
+             BRH:2\n\
+             DA:1,1\n\
+             DA:2,1\n\
+             DA:3,0\n\
+             DA:4,0\n\
+             DA:5,1\n\
+             LF:5\n\
+             LH:3\n\
+             end_of_record\n"
+        );
+    }
+}
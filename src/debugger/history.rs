@@ -0,0 +1,328 @@
+// Undo log for reverse debugging. Every step records the state it
+// overwrites, and every CHECKPOINT_INTERVAL steps the whole tape is saved
+// and the undo log is cleared. Going back past the last checkpoint restores
+// the one before it and runs forward again, replaying the recorded input and
+// swallowing output that was already written.
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{self, Read, Write},
+    rc::Rc,
+};
+
+use crate::bytecode::{Cell, Op, Program, Vm, VmError};
+
+const CHECKPOINT_INTERVAL: u64 = 50_000;
+// Older checkpoints are dropped once their tapes take up more than this,
+// which limits how far back you can go
+const MAX_CHECKPOINT_BYTES: usize = 256 << 20;
+
+#[derive(Default)]
+pub struct IoLog {
+    input: Vec<u8>,
+    input_position: usize,
+    output_position: usize,
+    output_written: usize,
+}
+
+pub struct ReplayInput<R> {
+    pub log: Rc<RefCell<IoLog>>,
+    pub inner: R,
+}
+
+impl<R: Read> Read for ReplayInput<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut log = self.log.borrow_mut();
+
+        if let Some(&byte) = log.input.get(log.input_position) {
+            if let Some(first) = buf.first_mut() {
+                *first = byte;
+                log.input_position += 1;
+                return Ok(1);
+            }
+        }
+
+        let read = self.inner.read(buf)?;
+        log.input.extend_from_slice(&buf[..read]);
+        log.input_position += read;
+        Ok(read)
+    }
+}
+
+pub struct ReplayOutput<W> {
+    pub log: Rc<RefCell<IoLog>>,
+    pub inner: W,
+}
+
+impl<W: Write> Write for ReplayOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut log = self.log.borrow_mut();
+
+        for byte in buf {
+            if log.output_position >= log.output_written {
+                self.inner.write_all(&[*byte])?;
+                log.output_written += 1;
+            }
+            log.output_position += 1;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct Checkpoint {
+    steps: u64,
+    pc: usize,
+    pointer: isize,
+    // Every cell takes as many bytes as its width, little endian
+    tape: Vec<u8>,
+    input_position: usize,
+    output_position: usize,
+}
+
+struct Undo {
+    pc: usize,
+    pointer: isize,
+    // Cell index and its old value
+    write: Option<(isize, Cell)>,
+    input_position: usize,
+    output_position: usize,
+}
+
+#[derive(Debug)]
+pub struct HistoryExhausted;
+
+pub struct History {
+    log: Rc<RefCell<IoLog>>,
+    checkpoints: VecDeque<Checkpoint>,
+    // Size of the tapes of all checkpoints
+    checkpoint_bytes: usize,
+    cell_bytes: usize,
+    undo: Vec<Undo>,
+}
+
+impl History {
+    pub fn new(vm: &Vm, log: Rc<RefCell<IoLog>>) -> Self {
+        let mut history = Self {
+            log,
+            checkpoints: VecDeque::new(),
+            checkpoint_bytes: 0,
+            cell_bytes: vm.cell_bits() as usize / 8,
+            undo: vec![],
+        };
+
+        history.checkpoint(vm);
+        history
+    }
+
+    fn checkpoint(&mut self, vm: &Vm) {
+        let log = self.log.borrow();
+
+        let mut tape = Vec::with_capacity(vm.tape().len() * self.cell_bytes);
+        for cell in vm.tape() {
+            tape.extend_from_slice(&cell.to_le_bytes()[..self.cell_bytes]);
+        }
+        self.checkpoint_bytes += tape.len();
+
+        self.checkpoints.push_back(Checkpoint {
+            steps: vm.steps(),
+            pc: vm.pc(),
+            pointer: vm.pointer(),
+            tape,
+            input_position: log.input_position,
+            output_position: log.output_position,
+        });
+        self.undo.clear();
+
+        // The newest checkpoint stays however large it is
+        while self.checkpoint_bytes > MAX_CHECKPOINT_BYTES && self.checkpoints.len() > 1 {
+            let oldest = self.checkpoints.pop_front().unwrap();
+            self.checkpoint_bytes -= oldest.tape.len();
+        }
+    }
+
+    // Runs a single op and records how to undo it
+    pub fn step(&mut self, vm: &mut Vm, program: &Program) -> Result<(), VmError> {
+        self.record_step(vm, program)?;
+
+        if vm.steps().is_multiple_of(CHECKPOINT_INTERVAL) {
+            self.checkpoint(vm);
+        }
+
+        Ok(())
+    }
+
+    fn record_step(&mut self, vm: &mut Vm, program: &Program) -> Result<(), VmError> {
+        let write = match program.ops[vm.pc()] {
+            Op::Add { offset, .. }
+            | Op::Set { offset, .. }
+            | Op::MulAdd { offset, .. }
+            // Cells past the end of a growable tape are 0 until it grows
+            | Op::Input { offset } => {
+                let index = vm.pointer() + offset as isize;
+                Some((index, vm.cell_at(index).unwrap_or(0)))
+            }
+            _ => None,
+        };
+
+        let log = self.log.borrow();
+        self.undo.push(Undo {
+            pc: vm.pc(),
+            pointer: vm.pointer(),
+            write,
+            input_position: log.input_position,
+            output_position: log.output_position,
+        });
+        drop(log);
+
+        if let Err(error) = vm.step() {
+            self.undo.pop();
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    // Undoes the last op, returning the cell it wrote to
+    pub fn step_back(
+        &mut self,
+        vm: &mut Vm,
+        program: &Program,
+    ) -> Result<Option<isize>, HistoryExhausted> {
+        if self.undo.is_empty() {
+            self.rebuild_previous_segment(vm, program)?;
+        }
+
+        let undo = self.undo.pop().ok_or(HistoryExhausted)?;
+
+        vm.restore(undo.pc, undo.pointer, vm.steps() - 1);
+        if let Some((index, value)) = undo.write {
+            vm.set_cell(index, value);
+        }
+
+        let mut log = self.log.borrow_mut();
+        log.input_position = undo.input_position;
+        log.output_position = undo.output_position;
+
+        Ok(undo.write.map(|(index, _)| index))
+    }
+
+    // Restores the checkpoint before the current one and runs forward to the
+    // current step again, so the undo log covers the steps in between
+    fn rebuild_previous_segment(
+        &mut self,
+        vm: &mut Vm,
+        program: &Program,
+    ) -> Result<(), HistoryExhausted> {
+        if vm.steps() == 0 || self.checkpoints.len() < 2 {
+            return Err(HistoryExhausted);
+        }
+
+        let target = vm.steps();
+        if let Some(current) = self.checkpoints.pop_back() {
+            self.checkpoint_bytes -= current.tape.len();
+        }
+
+        let checkpoint = self.checkpoints.back().ok_or(HistoryExhausted)?;
+        vm.restore(checkpoint.pc, checkpoint.pointer, checkpoint.steps);
+        let tape: Vec<Cell> = checkpoint
+            .tape
+            .chunks(self.cell_bytes)
+            .map(|cell| {
+                let mut bytes = [0; 8];
+                bytes[..self.cell_bytes].copy_from_slice(cell);
+                Cell::from_le_bytes(bytes)
+            })
+            .collect();
+        vm.set_tape(&tape);
+
+        let mut log = self.log.borrow_mut();
+        log.input_position = checkpoint.input_position;
+        log.output_position = checkpoint.output_position;
+        drop(log);
+
+        // These steps already ran once, so they can't fail now
+        while vm.steps() < target {
+            self.record_step(vm, program)
+                .expect("Replaying recorded steps failed");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::{compile_source, Limits};
+
+    // The pc, pointer, first cells and step count
+    fn state(vm: &Vm) -> (usize, isize, Vec<Cell>, u64) {
+        (vm.pc(), vm.pointer(), vm.tape()[..4].to_vec(), vm.steps())
+    }
+
+    #[test]
+    fn step_back_across_checkpoints() {
+        let (program, config) = compile_source("-[>-[>+<-]<-]", &[]);
+        let log = Rc::new(RefCell::new(IoLog::default()));
+        let mut input = ReplayInput {
+            log: log.clone(),
+            inner: io::empty(),
+        };
+        let mut output = ReplayOutput {
+            log: log.clone(),
+            inner: io::sink(),
+        };
+        let mut vm = Vm::new(&program, config, Limits::default(), &mut input, &mut output);
+        let mut history = History::new(&vm, log);
+
+        let mut states = vec![];
+        while vm.steps() < CHECKPOINT_INTERVAL + 10_000 {
+            states.push(state(&vm));
+            history.step(&mut vm, &program).unwrap();
+        }
+
+        for expected in states.iter().rev() {
+            history.step_back(&mut vm, &program).unwrap();
+            assert_eq!(state(&vm), *expected);
+        }
+
+        assert!(history.step_back(&mut vm, &program).is_err());
+    }
+
+    #[test]
+    fn input_and_output_are_replayed() {
+        let (program, config) = compile_source(",.,.", &[]);
+        let log = Rc::new(RefCell::new(IoLog::default()));
+        let mut input = ReplayInput {
+            log: log.clone(),
+            inner: &b"ab"[..],
+        };
+        let mut written = vec![];
+        let mut output = ReplayOutput {
+            log: log.clone(),
+            inner: &mut written,
+        };
+        let mut vm = Vm::new(&program, config, Limits::default(), &mut input, &mut output);
+        let mut history = History::new(&vm, log);
+
+        for _ in 0..4 {
+            history.step(&mut vm, &program).unwrap();
+        }
+        for _ in 0..4 {
+            history.step_back(&mut vm, &program).unwrap();
+        }
+        for _ in 0..4 {
+            history.step(&mut vm, &program).unwrap();
+        }
+
+        assert_eq!(vm.tape()[0], b'b' as Cell);
+        drop(vm);
+        assert_eq!(written, b"ab");
+    }
+}
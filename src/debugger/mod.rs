@@ -0,0 +1,560 @@
+mod history;
+
+use std::{
+    cell::RefCell,
+    fs,
+    io::{self, BufReader, Read, Write},
+    process,
+    rc::Rc,
+};
+
+use history::{History, IoLog, ReplayInput, ReplayOutput};
+
+use crate::{
+    bytecode::{self, cell_mask, cell_range, cell_value, Cell, Limits, Program, Vm, VmError},
+    cli::{DebugArgs, UNBOUNDED_CELL_BITS},
+    codegen_options, parse_source,
+    span::Position,
+    tape_view::{tape_window, CellFormat},
+    vm_config,
+};
+
+const HELP: &str = "\
+s, step [count]           run the next op
+n, next                   run the next op, stepping over whole loops
+c, continue               run until a breakpoint, a watchpoint or the end
+rs, reverse-step [count]  undo the last op
+rc, reverse-continue      run backwards to a breakpoint, a watchpoint or the start
+last-write <cell>         run backwards to the op that last wrote to the cell
+b, break <line:column>    stop before the op at the position
+w, watch <cell> [value]   stop when a cell changes, or becomes value
+w, watch ptr <start>..<end>
+                          stop when the pointer enters the range
+d, delete                 remove all breakpoints and watchpoints
+i, info                   list breakpoints and watchpoints
+t, tape [radius]          show the cells around the pointer
+f, format <dec|hex|char>  change how cells are displayed
+l, list                   show the current source position
+q, quit                   exit the debugger
+
+A `#` in the source sets a breakpoint on the next command.";
+
+enum Watchpoint {
+    Cell { index: isize, value: Option<Cell> },
+    Pointer { start: isize, end: isize },
+}
+
+enum Stop {
+    Step,
+    Breakpoint(Position),
+    Watchpoint(usize),
+    LastWrite(isize),
+    Finished,
+    HistoryStart,
+    Error(VmError),
+}
+
+// What watchpoints compare between two points in time
+struct Snapshot {
+    cells: Vec<Option<Cell>>,
+    pointer: isize,
+}
+
+struct Debugger<'a> {
+    vm: Vm<'a>,
+    history: History,
+    program: Program,
+    path: String,
+    source: Vec<String>,
+    // Op indices to stop before, with the position they were set at
+    breakpoints: Vec<(usize, Position)>,
+    watchpoints: Vec<Watchpoint>,
+    format: CellFormat,
+}
+
+pub fn run(args: &DebugArgs) {
+    // Watchpoints and reverse steps work on cells with a fixed width
+    if args.machine.cell_bits == UNBOUNDED_CELL_BITS {
+        eprintln!("The debugger doesn't support --cell-bits unbounded");
+        process::exit(1);
+    }
+
+    let source = fs::read_to_string(&args.input_path)
+        .unwrap_or_else(|_| panic!("Failed to read source {}", args.input_path));
+    let program = bytecode::compile(
+        &parse_source(&args.input_path, source.clone()),
+        &codegen_options(false, &args.machine),
+    );
+
+    let inner: Box<dyn Read> = match &args.input {
+        Some(path) => Box::new(BufReader::new(
+            fs::File::open(path).unwrap_or_else(|_| panic!("Failed to open input {path}")),
+        )),
+        None => Box::new(io::stdin()),
+    };
+
+    // Input and output go through the log so that steps can be replayed
+    let log = Rc::new(RefCell::new(IoLog::default()));
+    let mut input = ReplayInput {
+        log: log.clone(),
+        inner,
+    };
+    let mut output = ReplayOutput {
+        log: log.clone(),
+        inner: io::stdout(),
+    };
+
+    let vm = Vm::new(
+        &program,
+        vm_config(&args.machine),
+        Limits::default(),
+        &mut input,
+        &mut output,
+    );
+    let mut debugger = Debugger {
+        history: History::new(&vm, log),
+        vm,
+        program,
+        path: args.input_path.clone(),
+        source: source.lines().map(str::to_owned).collect(),
+        breakpoints: vec![],
+        watchpoints: vec![],
+        format: CellFormat::Decimal,
+    };
+
+    for position in hash_positions(&source) {
+        debugger.add_breakpoint(position);
+    }
+
+    debugger.list();
+
+    loop {
+        print!("(bfdb) ");
+        io::stdout().flush().expect("Failed to write prompt");
+
+        let mut line = String::new();
+        if io::stdin()
+            .read_line(&mut line)
+            .expect("Failed to read line")
+            == 0
+        {
+            println!();
+            return;
+        }
+
+        if !debugger.command(line.trim()) {
+            return;
+        }
+    }
+}
+
+// Positions of every `#`, counted the same way as the lexer does
+fn hash_positions(source: &str) -> Vec<Position> {
+    let mut positions = vec![];
+
+    for (line, text) in source.lines().enumerate() {
+        for (column, c) in text.chars().enumerate() {
+            if c == '#' {
+                positions.push(Position::new(line + 1, column + 1));
+            }
+        }
+    }
+
+    positions
+}
+
+fn parse_position(text: &str) -> Option<Position> {
+    let (line, column) = text.split_once(':')?;
+    Some(Position::new(line.parse().ok()?, column.parse().ok()?))
+}
+
+impl Debugger<'_> {
+    // Returns false when the debugger should exit
+    fn command(&mut self, command: &str) -> bool {
+        let mut words = command.split_whitespace();
+        let name = words.next().unwrap_or("");
+        let arguments: Vec<&str> = words.collect();
+
+        match (name, arguments.as_slice()) {
+            ("s" | "step", []) => self.resume(|_| true),
+            ("s" | "step", [count]) => match count.parse::<u64>() {
+                Ok(count) => {
+                    let target = self.vm.steps() + count;
+                    self.resume(|debugger| debugger.vm.steps() >= target)
+                }
+                Err(_) => println!("error: invalid count `{count}`"),
+            },
+            ("n" | "next", []) => self.next(),
+            ("c" | "continue", []) => self.resume(|_| false),
+            ("rs" | "reverse-step", []) => self.reverse(|_, _| Some(Stop::Step)),
+            ("rs" | "reverse-step", [count]) => match count.parse::<u64>() {
+                Ok(count) => {
+                    let target = self.vm.steps().saturating_sub(count);
+                    self.reverse(|debugger, _| {
+                        (debugger.vm.steps() <= target).then_some(Stop::Step)
+                    })
+                }
+                Err(_) => println!("error: invalid count `{count}`"),
+            },
+            ("rc" | "reverse-continue", []) => self.reverse(|_, _| None),
+            ("last-write", [cell]) => match cell.parse::<isize>() {
+                Ok(cell) => self
+                    .reverse(|_, written| (written == Some(cell)).then_some(Stop::LastWrite(cell))),
+                Err(_) => println!("error: invalid cell `{cell}`"),
+            },
+            ("b" | "break", [position]) => match parse_position(position) {
+                Some(position) => self.add_breakpoint(position),
+                None => println!("error: expected a position like 12:7"),
+            },
+            ("w" | "watch", ["ptr", range]) => self.watch_pointer(range),
+            ("w" | "watch", [cell]) => self.watch_cell(cell, None),
+            ("w" | "watch", [cell, value]) => self.watch_cell(cell, Some(value)),
+            ("d" | "delete", []) => {
+                self.breakpoints.clear();
+                self.watchpoints.clear();
+            }
+            ("i" | "info", []) => self.info(),
+            ("t" | "tape", []) => self.tape(8),
+            ("t" | "tape", [radius]) => match radius.parse() {
+                Ok(radius) => self.tape(radius),
+                Err(_) => println!("error: invalid radius `{radius}`"),
+            },
+            ("f" | "format", ["dec"]) => self.format = CellFormat::Decimal,
+            ("f" | "format", ["hex"]) => self.format = CellFormat::Hex,
+            ("f" | "format", ["char"]) => self.format = CellFormat::Char,
+            ("l" | "list", []) => self.list(),
+            ("h" | "help", []) => println!("{HELP}"),
+            ("q" | "quit", []) => return false,
+            ("", []) => {}
+            _ => println!("error: unknown command `{command}`, try `help`"),
+        }
+
+        true
+    }
+
+    fn add_breakpoint(&mut self, position: Position) {
+        let spans = &self.program.spans;
+
+        // The op at the position, or the first one after it
+        let pc = spans
+            .iter()
+            .position(|span| span.start <= position && position <= span.end)
+            .or_else(|| spans.iter().position(|span| span.start > position));
+
+        match pc {
+            Some(pc) => self.breakpoints.push((pc, position)),
+            None => println!("error: no command at or after {position}"),
+        }
+    }
+
+    fn watch_cell(&mut self, cell: &str, value: Option<&str>) {
+        let Ok(index) = cell.parse() else {
+            println!("error: invalid cell `{cell}`");
+            return;
+        };
+
+        let (bits, signed) = (self.program.cell_bits, self.program.cell_signed);
+        let (min, max) = cell_range(bits, signed);
+        let value = match value.map(str::parse::<i128>) {
+            Some(Ok(value)) if (min..=max).contains(&value) => {
+                Some(value as Cell & cell_mask(bits))
+            }
+            Some(_) => {
+                println!("error: invalid value, expected {min} to {max}");
+                return;
+            }
+            None => None,
+        };
+
+        self.watchpoints.push(Watchpoint::Cell { index, value });
+    }
+
+    fn watch_pointer(&mut self, range: &str) {
+        let range = range
+            .split_once("..")
+            .and_then(|(start, end)| Some((start.parse().ok()?, end.parse().ok()?)));
+
+        match range {
+            Some((start, end)) => self.watchpoints.push(Watchpoint::Pointer { start, end }),
+            None => println!("error: expected a range like 10..20"),
+        }
+    }
+
+    fn cell(&self, index: isize) -> Option<Cell> {
+        self.vm.cell_at(index)
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            cells: self
+                .watchpoints
+                .iter()
+                .map(|watchpoint| match watchpoint {
+                    Watchpoint::Cell { index, .. } => self.cell(*index),
+                    Watchpoint::Pointer { .. } => None,
+                })
+                .collect(),
+            pointer: self.vm.pointer(),
+        }
+    }
+
+    // The first watchpoint that fires going from `earlier` to `later`
+    fn triggered(&self, earlier: &Snapshot, later: &Snapshot) -> Option<usize> {
+        self.watchpoints
+            .iter()
+            .enumerate()
+            .position(|(i, watchpoint)| match *watchpoint {
+                Watchpoint::Cell { value, .. } => {
+                    let (before, after) = (earlier.cells[i], later.cells[i]);
+                    after != before && value.is_none_or(|value| after == Some(value))
+                }
+                Watchpoint::Pointer { start, end } => {
+                    let range = start..=end;
+                    !range.contains(&earlier.pointer) && range.contains(&later.pointer)
+                }
+            })
+    }
+
+    fn breakpoint(&self) -> Option<Position> {
+        self.breakpoints
+            .iter()
+            .find(|(pc, _)| *pc == self.vm.pc())
+            .map(|(_, position)| *position)
+    }
+
+    // Steps until `done` returns true or something else stops execution
+    fn resume(&mut self, mut done: impl FnMut(&Self) -> bool) {
+        let stop = loop {
+            if self.vm.is_finished() {
+                break Stop::Finished;
+            }
+
+            let earlier = self.snapshot();
+            if let Err(error) = self.history.step(&mut self.vm, &self.program) {
+                break Stop::Error(error);
+            }
+
+            if let Some(index) = self.triggered(&earlier, &self.snapshot()) {
+                break Stop::Watchpoint(index);
+            }
+
+            if done(self) {
+                break Stop::Step;
+            }
+
+            if let Some(position) = self.breakpoint() {
+                break Stop::Breakpoint(position);
+            }
+        };
+
+        self.vm.flush().expect("Failed to write output");
+        self.report(stop);
+    }
+
+    // Steps backwards until `done` returns a reason to stop or something else
+    // stops execution. `done` also gets the cell written by the undone op.
+    fn reverse(&mut self, mut done: impl FnMut(&Self, Option<isize>) -> Option<Stop>) {
+        let stop = loop {
+            let later = self.snapshot();
+            let written = match self.history.step_back(&mut self.vm, &self.program) {
+                Ok(written) => written,
+                Err(_) => break Stop::HistoryStart,
+            };
+
+            if let Some(index) = self.triggered(&self.snapshot(), &later) {
+                break Stop::Watchpoint(index);
+            }
+
+            if let Some(stop) = done(self, written) {
+                break stop;
+            }
+
+            if let Some(position) = self.breakpoint() {
+                break Stop::Breakpoint(position);
+            }
+        };
+
+        self.report(stop);
+    }
+
+    // Steps over a whole loop when stopped at its start
+    fn next(&mut self) {
+        match self.program.ops.get(self.vm.pc()) {
+            Some(bytecode::Op::JumpIfZero(end)) => {
+                let end = *end as usize;
+                self.resume(|debugger| debugger.vm.pc() == end)
+            }
+            _ => self.resume(|_| true),
+        }
+    }
+
+    fn report(&self, stop: Stop) {
+        match stop {
+            Stop::Step => {}
+            Stop::Breakpoint(position) => println!("breakpoint at {position}"),
+            Stop::Watchpoint(index) => match self.watchpoints[index] {
+                Watchpoint::Cell { index, .. } => {
+                    println!(
+                        "watchpoint: cell {index} = {}",
+                        self.format
+                            .format(self.cell(index).unwrap_or(0), self.vm.signed_bits())
+                    )
+                }
+                Watchpoint::Pointer { .. } => {
+                    println!("watchpoint: pointer = {}", self.vm.pointer())
+                }
+            },
+            Stop::LastWrite(cell) => println!(
+                "cell {cell} was last written by this op, it was {} before",
+                self.format
+                    .format(self.cell(cell).unwrap_or(0), self.vm.signed_bits())
+            ),
+            Stop::Finished => {
+                println!("program finished after {} steps", self.vm.steps());
+                return;
+            }
+            Stop::HistoryStart => println!("reached the start of the recorded history"),
+            Stop::Error(error) => println!("error: {error}"),
+        }
+
+        self.list();
+    }
+
+    fn info(&self) {
+        for (i, (_, position)) in self.breakpoints.iter().enumerate() {
+            println!("breakpoint {i} at {position}");
+        }
+
+        for (i, watchpoint) in self.watchpoints.iter().enumerate() {
+            match watchpoint {
+                Watchpoint::Cell { index, value: None } => {
+                    println!("watchpoint {i} on cell {index}")
+                }
+                Watchpoint::Cell {
+                    index,
+                    value: Some(value),
+                } => println!(
+                    "watchpoint {i} on cell {index} = {}",
+                    cell_value(*value, self.program.cell_bits, self.program.cell_signed)
+                ),
+                Watchpoint::Pointer { start, end } => {
+                    println!("watchpoint {i} on pointer in {start}..{end}")
+                }
+            }
+        }
+    }
+
+    fn tape(&self, radius: isize) {
+        println!("pointer = {}", self.vm.pointer());
+        println!("{}", tape_window(&self.vm, radius, self.format));
+    }
+
+    // Prints the source line of the next op with a marker under it
+    fn list(&self) {
+        let Some(span) = self.program.spans.get(self.vm.pc()) else {
+            println!("program finished after {} steps", self.vm.steps());
+            return;
+        };
+
+        let position = span.start;
+        let line = self
+            .source
+            .get(position.line - 1)
+            .map_or("", String::as_str);
+
+        println!("{}:{position}", self.path);
+        println!("{:>5} | {line}", position.line);
+
+        let width = if span.start.line == span.end.line {
+            span.end.column - span.start.column + 1
+        } else {
+            1
+        };
+        println!(
+            "      | {}{}",
+            " ".repeat(position.column - 1),
+            "^".repeat(width)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::compile_source;
+
+    // Runs the debugger commands and returns the pc, the pointer and the first cells
+    fn debug(source: &str, commands: &[&str]) -> (usize, isize, Vec<Cell>) {
+        let (program, config) = compile_source(source, &[]);
+        let log = Rc::new(RefCell::new(IoLog::default()));
+        let mut input = ReplayInput {
+            log: log.clone(),
+            inner: io::empty(),
+        };
+        let mut output = ReplayOutput {
+            log: log.clone(),
+            inner: io::sink(),
+        };
+
+        let vm = Vm::new(&program, config, Limits::default(), &mut input, &mut output);
+        let mut debugger = Debugger {
+            history: History::new(&vm, log),
+            vm,
+            program,
+            path: "test.bf".to_string(),
+            source: source.lines().map(str::to_owned).collect(),
+            breakpoints: vec![],
+            watchpoints: vec![],
+            format: CellFormat::Decimal,
+        };
+
+        for position in hash_positions(source) {
+            debugger.add_breakpoint(position);
+        }
+
+        for command in commands {
+            assert!(debugger.command(command));
+        }
+
+        (
+            debugger.vm.pc(),
+            debugger.vm.pointer(),
+            debugger.vm.tape()[..4].to_vec(),
+        )
+    }
+
+    #[test]
+    fn positions() {
+        assert_eq!(
+            hash_positions("+#\n>>#"),
+            [Position::new(1, 2), Position::new(2, 3)]
+        );
+
+        assert_eq!(parse_position("12:7"), Some(Position::new(12, 7)));
+        assert_eq!(parse_position("12"), None);
+        assert_eq!(parse_position("a:7"), None);
+    }
+
+    #[test]
+    fn breakpoints() {
+        assert_eq!(debug("+[-]>+", &["b 1:5", "c"]), (4, 0, vec![0, 0, 0, 0]));
+        assert_eq!(debug("+#>+", &["c"]), (1, 0, vec![1, 0, 0, 0]));
+    }
+
+    #[test]
+    fn next_steps_over_loops() {
+        let (pc, pointer, tape) = debug("++[->+<]>", &["n", "n"]);
+
+        assert_eq!((pointer, tape), (0, vec![0, 2, 0, 0]));
+        assert!(matches!(
+            compile_source("++[->+<]>", &[]).0.ops[pc],
+            bytecode::Op::Move(1)
+        ));
+    }
+
+    #[test]
+    fn watchpoints() {
+        assert_eq!(debug("+>+>+<+", &["w 1 2", "c"]).2, [1, 2, 1, 0]);
+        assert_eq!(debug(">+>+>+>", &["w ptr 2..3", "c"]).1, 2);
+    }
+}
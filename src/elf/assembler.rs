@@ -0,0 +1,531 @@
+// Assembles the AT&T syntax of the assembly backend, just the directives and
+// instructions the --no-libc runtime and the commands use. Every line is a
+// label, a directive or an instruction and comments take up whole lines.
+
+use std::collections::HashMap;
+
+use super::x86::{self, Base, Memory, Operand};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Section {
+    Text,
+    Rodata,
+    Data,
+    Bss,
+}
+
+enum FixupKind {
+    // Distance from the end of the instruction, 32 bits
+    Relative { end: usize },
+    // Address, 64 bits
+    Absolute,
+}
+
+struct Fixup {
+    section: Section,
+    offset: usize,
+    label: String,
+    kind: FixupKind,
+}
+
+pub struct Program {
+    pub text: Vec<u8>,
+    pub rodata: Vec<u8>,
+    pub data: Vec<u8>,
+    pub bss_size: u64,
+    labels: HashMap<String, (Section, u64)>,
+    fixups: Vec<Fixup>,
+}
+
+impl Program {
+    fn section(&mut self, section: Section) -> &mut Vec<u8> {
+        match section {
+            Section::Text => &mut self.text,
+            Section::Rodata => &mut self.rodata,
+            Section::Data => &mut self.data,
+            Section::Bss => unreachable!(),
+        }
+    }
+
+    fn offset(&self, section: Section) -> u64 {
+        match section {
+            Section::Text => self.text.len() as u64,
+            Section::Rodata => self.rodata.len() as u64,
+            Section::Data => self.data.len() as u64,
+            Section::Bss => self.bss_size,
+        }
+    }
+
+    // Fills in the addresses of labels once every section has an address and
+    // returns the address of `entry`
+    pub fn link(&mut self, address: impl Fn(Section) -> u64, entry: &str) -> Result<u64, String> {
+        let label_address = |label: &str| {
+            self.labels
+                .get(label)
+                .map(|(section, offset)| address(*section) + offset)
+                .ok_or_else(|| format!("undefined label {label}"))
+        };
+
+        let mut patches = vec![];
+        for fixup in &self.fixups {
+            let target = label_address(&fixup.label)?;
+
+            let patch = match fixup.kind {
+                FixupKind::Relative { end } => {
+                    let bytes = match fixup.section {
+                        Section::Text => &self.text,
+                        Section::Rodata => &self.rodata,
+                        _ => &self.data,
+                    };
+                    let addend = i32::from_le_bytes(
+                        bytes[fixup.offset..fixup.offset + 4].try_into().unwrap(),
+                    );
+                    let distance = target as i64 + addend as i64
+                        - (address(fixup.section) + end as u64) as i64;
+
+                    i32::try_from(distance)
+                        .map_err(|_| format!("{} is too far away", fixup.label))?
+                        .to_le_bytes()
+                        .to_vec()
+                }
+                FixupKind::Absolute => target.to_le_bytes().to_vec(),
+            };
+
+            patches.push((fixup.section, fixup.offset, patch));
+        }
+
+        let entry = label_address(entry)?;
+
+        for (section, offset, patch) in patches {
+            self.section(section)[offset..offset + patch.len()].copy_from_slice(&patch);
+        }
+
+        Ok(entry)
+    }
+}
+
+struct Assembler {
+    program: Program,
+    section: Section,
+    // Defined with .set
+    constants: HashMap<String, i64>,
+}
+
+pub fn assemble(source: &str) -> Result<Program, String> {
+    let mut assembler = Assembler {
+        program: Program {
+            text: vec![],
+            rodata: vec![],
+            data: vec![],
+            bss_size: 0,
+            labels: HashMap::new(),
+            fixups: vec![],
+        },
+        section: Section::Text,
+        constants: HashMap::new(),
+    };
+
+    for (number, line) in source.lines().enumerate() {
+        assembler
+            .line(line.trim())
+            .map_err(|error| format!("line {}: {error}: {}", number + 1, line.trim()))?;
+    }
+
+    Ok(assembler.program)
+}
+
+impl Assembler {
+    fn line(&mut self, line: &str) -> Result<(), String> {
+        if line.is_empty() || line.starts_with("//") {
+            return Ok(());
+        }
+
+        if let Some(label) = line.strip_suffix(':') {
+            let offset = self.program.offset(self.section);
+            return self.define(label, self.section, offset);
+        }
+
+        let (name, arguments) = line
+            .split_once(char::is_whitespace)
+            .map(|(name, arguments)| (name, arguments.trim()))
+            .unwrap_or((line, ""));
+
+        if name.starts_with('.') {
+            self.directive(name, arguments)
+        } else {
+            self.instruction(name, arguments)
+        }
+    }
+
+    fn define(&mut self, label: &str, section: Section, offset: u64) -> Result<(), String> {
+        if self
+            .program
+            .labels
+            .insert(label.to_string(), (section, offset))
+            .is_some()
+        {
+            return Err(format!("{label} is already defined"));
+        }
+
+        Ok(())
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if self.section == Section::Bss {
+            return Err("only .lcomm can reserve space in .bss".to_string());
+        }
+
+        self.program.section(self.section).extend(bytes);
+        Ok(())
+    }
+
+    fn directive(&mut self, name: &str, arguments: &str) -> Result<(), String> {
+        match name {
+            ".text" => self.section = Section::Text,
+            ".data" => self.section = Section::Data,
+            ".bss" => self.section = Section::Bss,
+            ".section" if arguments == ".rodata" => self.section = Section::Rodata,
+            // Marks the stack as not executable, the executable always does
+            ".section" if arguments.starts_with(".note.GNU-stack") => {}
+            // The entry point is found by name
+            ".globl" => {}
+            ".set" => {
+                let (name, value) = arguments
+                    .split_once(',')
+                    .ok_or("expected a name and value")?;
+                let value = self.value(value.trim())?;
+                self.constants.insert(name.trim().to_string(), value);
+            }
+            ".lcomm" => {
+                let (name, size) = arguments
+                    .split_once(',')
+                    .ok_or("expected a name and size")?;
+                let size = self.value(size.trim())? as u64;
+                let offset = self.program.bss_size.next_multiple_of(16);
+
+                self.define(name.trim(), Section::Bss, offset)?;
+                self.program.bss_size = offset + size;
+            }
+            ".byte" => {
+                for value in arguments.split(',') {
+                    let value = self.value(value.trim())?;
+                    if !(-128..256).contains(&value) {
+                        return Err(format!("{value} isn't a byte"));
+                    }
+                    self.emit(&[value as u8])?;
+                }
+            }
+            ".quad" => {
+                for value in arguments.split(',').map(str::trim) {
+                    match self.value(value) {
+                        Ok(value) => self.emit(&value.to_le_bytes())?,
+                        Err(_) => {
+                            self.program.fixups.push(Fixup {
+                                section: self.section,
+                                offset: self.program.offset(self.section) as usize,
+                                label: value.to_string(),
+                                kind: FixupKind::Absolute,
+                            });
+                            self.emit(&[0; 8])?;
+                        }
+                    }
+                }
+            }
+            ".string" => {
+                let mut string = unescape(arguments)?;
+                string.push(0);
+                self.emit(&string)?;
+            }
+            _ => return Err(format!("unknown directive {name}")),
+        }
+
+        Ok(())
+    }
+
+    fn instruction(&mut self, mnemonic: &str, arguments: &str) -> Result<(), String> {
+        let operands = split_operands(arguments)
+            .into_iter()
+            .map(|operand| self.operand(operand))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let encoded = x86::encode(mnemonic, &operands)?;
+
+        let start = self.program.offset(self.section) as usize;
+        if let Some((offset, label)) = encoded.fixup {
+            self.program.fixups.push(Fixup {
+                section: self.section,
+                offset: start + offset,
+                label,
+                kind: FixupKind::Relative {
+                    end: start + encoded.bytes.len(),
+                },
+            });
+        }
+
+        self.emit(&encoded.bytes)
+    }
+
+    fn operand(&self, operand: &str) -> Result<Operand, String> {
+        if let Some(name) = operand.strip_prefix('%') {
+            return x86::register(name)
+                .map(Operand::Register)
+                .ok_or_else(|| format!("unknown register {operand}"));
+        }
+
+        if let Some(value) = operand.strip_prefix('$') {
+            return self.value(value).map(Operand::Immediate);
+        }
+
+        let Some((displacement, registers)) = operand.split_once('(') else {
+            return Ok(Operand::Label(operand.to_string()));
+        };
+        let registers = registers
+            .strip_suffix(')')
+            .ok_or_else(|| format!("expected ) after {operand}"))?;
+        let registers: Vec<_> = registers.split(',').map(str::trim).collect();
+
+        let register = |name: &str| {
+            name.strip_prefix('%')
+                .and_then(x86::register)
+                .filter(|register| register.size == 8)
+                .map(|register| register.number)
+                .ok_or_else(|| format!("{name} can't address memory"))
+        };
+
+        // Labels can only be relative to %rip
+        if let ["%rip"] = registers[..] {
+            return Ok(Operand::Memory(Memory {
+                base: Base::Rip(displacement.to_string()),
+                index: None,
+                displacement: 0,
+            }));
+        }
+
+        let displacement = match displacement {
+            "" => 0,
+            displacement => self.value(displacement)?,
+        };
+        if i32::try_from(displacement).is_err() {
+            return Err(format!("displacement {displacement} doesn't fit"));
+        }
+
+        let index = match registers[..] {
+            [_] => None,
+            [_, index] => Some((register(index)?, 1)),
+            [_, index, scale] => {
+                let scale = self.value(scale)?;
+                if ![1, 2, 4, 8].contains(&scale) {
+                    return Err(format!("scale {scale} isn't 1, 2, 4 or 8"));
+                }
+                Some((register(index)?, scale as u8))
+            }
+            _ => return Err(format!("unsupported operand {operand}")),
+        };
+
+        Ok(Operand::Memory(Memory {
+            base: Base::Register(register(registers[0])?),
+            index,
+            displacement,
+        }))
+    }
+
+    // A number, character or constant. Numbers may be up to 64 bits, signed
+    // or not.
+    fn value(&self, value: &str) -> Result<i64, String> {
+        if let Some(constant) = self.constants.get(value) {
+            return Ok(*constant);
+        }
+
+        if let Some(character) = value
+            .strip_prefix('\'')
+            .and_then(|value| value.strip_suffix('\''))
+        {
+            let mut characters = character.chars();
+            return match (characters.next(), characters.next()) {
+                (Some(character), None) if character.is_ascii() => Ok(character as i64),
+                _ => Err(format!("unsupported character {value}")),
+            };
+        }
+
+        let (negative, digits) = match value.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, value),
+        };
+        let number = match digits.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => digits.parse::<u64>(),
+        }
+        .map_err(|_| format!("expected a number instead of {value}"))?;
+
+        if negative {
+            i64::try_from(-(number as i128)).map_err(|_| format!("{value} doesn't fit in 64 bits"))
+        } else {
+            Ok(number as i64)
+        }
+    }
+}
+
+// Splits at the commas between operands but not the ones inside parentheses
+fn split_operands(arguments: &str) -> Vec<&str> {
+    if arguments.is_empty() {
+        return vec![];
+    }
+
+    let mut operands = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, character) in arguments.char_indices() {
+        match character {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                operands.push(arguments[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    operands.push(arguments[start..].trim());
+
+    operands
+}
+
+// The bytes of a string literal, with the escapes `escape_string` makes
+fn unescape(literal: &str) -> Result<Vec<u8>, String> {
+    let string = literal
+        .strip_prefix('"')
+        .and_then(|literal| literal.strip_suffix('"'))
+        .ok_or_else(|| format!("expected a string instead of {literal}"))?;
+
+    let mut bytes = vec![];
+    let mut characters = string.chars();
+
+    while let Some(character) = characters.next() {
+        let character = match character {
+            '\\' => match characters.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some(character @ ('"' | '\\')) => character,
+                _ => return Err(format!("unsupported escape in {literal}")),
+            },
+            character => character,
+        };
+
+        let mut buffer = [0; 4];
+        bytes.extend(character.encode_utf8(&mut buffer).as_bytes());
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(section: Section) -> u64 {
+        match section {
+            Section::Text => 0x1000,
+            Section::Rodata => 0x2000,
+            Section::Data => 0x3000,
+            Section::Bss => 0x4000,
+        }
+    }
+
+    #[test]
+    fn sections() {
+        let program = assemble(
+            "        .set    SIZE, 0x20
+	.section .rodata
+message:
+        .string \"a\\tb\\n\"
+	.data
+        .byte   1, -1, 'A'
+        .quad   SIZE
+	.bss
+        .lcomm  first, 3
+        .lcomm  second, SIZE
+	.section .note.GNU-stack,\"\",@progbits",
+        )
+        .unwrap();
+
+        assert!(program.text.is_empty());
+        assert_eq!(program.rodata, b"a\tb\n\0");
+        assert_eq!(program.data, [1, 0xff, b'A', 0x20, 0, 0, 0, 0, 0, 0, 0]);
+        // Every .lcomm starts on 16 bytes
+        assert_eq!(program.labels["second"], (Section::Bss, 16));
+        assert_eq!(program.bss_size, 48);
+    }
+
+    #[test]
+    fn link() {
+        let mut program = assemble(
+            "	.globl	_start
+	.data
+pointer:
+        .quad   _start
+	.text
+back:
+        ret
+_start:
+        jmp     back
+        leaq    pointer(%rip), %rdi
+        call    forward
+forward:
+        ret",
+        )
+        .unwrap();
+
+        assert_eq!(program.link(address, "_start").unwrap(), 0x1001);
+        assert_eq!(program.data, 0x1001u64.to_le_bytes());
+        // Distances from the end of each instruction
+        assert_eq!(program.text[2..6], (-6i32).to_le_bytes());
+        assert_eq!(program.text[9..13], (0x3000 - 0x100d_i32).to_le_bytes());
+        assert_eq!(program.text[14..18], 0i32.to_le_bytes());
+    }
+
+    #[test]
+    fn link_errors() {
+        let mut program = assemble("_start:\n        jmp     nowhere").unwrap();
+        assert_eq!(
+            program.link(address, "_start").unwrap_err(),
+            "undefined label nowhere"
+        );
+
+        // Beyond the reach of a 32-bit displacement
+        let mut program =
+            assemble("_start:\n        leaq    far(%rip), %rax\n\t.bss\n        .lcomm  far, 1")
+                .unwrap();
+        let far = |section| match section {
+            Section::Bss => 0x1_0000_0000,
+            section => address(section),
+        };
+        assert_eq!(
+            program.link(far, "_start").unwrap_err(),
+            "far is too far away"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            assemble("        ret\n        frobq   %rax").err().unwrap(),
+            "line 2: unknown instruction frobq: frobq   %rax"
+        );
+        assert!(assemble("a:\na:").is_err());
+        assert!(assemble("        .byte   256").is_err());
+        assert!(assemble("        .align  16").is_err());
+        assert!(assemble("\t.bss\n        .byte   0").is_err());
+        assert!(assemble("        movq    %eax, (%eax)").is_err());
+        assert!(assemble("        movq    (%rax,%rcx,3), %rax").is_err());
+    }
+
+    #[test]
+    fn operands() {
+        assert_eq!(
+            split_operands("$1, 8(%rax,%rcx,8)"),
+            ["$1", "8(%rax,%rcx,8)"]
+        );
+        assert!(split_operands("").is_empty());
+    }
+}
@@ -0,0 +1,335 @@
+// Static ELF executables written without `as` or `ld`. The assembly of the
+// --no-libc runtime is assembled here and laid out in two segments: the
+// headers, .text and .rodata, readable and executable, then .data and .bss on
+// the pages after them, readable and writable.
+
+mod assembler;
+mod x86;
+
+use assembler::Section;
+
+// Where `ld` puts static executables too
+const BASE_ADDRESS: u64 = 0x400000;
+const PAGE_SIZE: u64 = 0x1000;
+
+const HEADER_SIZE: u64 = 64;
+const PROGRAM_HEADER_SIZE: u64 = 56;
+const PROGRAM_HEADERS: u64 = 3;
+const SECTION_HEADER_SIZE: u64 = 64;
+
+const PT_LOAD: u32 = 1;
+const PT_GNU_STACK: u32 = 0x6474e551;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_STRTAB: u32 = 3;
+const SHT_NOBITS: u32 = 8;
+const SHF_WRITE: u64 = 1;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+
+// Section names, the index of each is where its name starts
+const SECTION_NAMES: &[u8] = b"\0.text\0.rodata\0.data\0.bss\0.shstrtab\0";
+
+struct SectionHeader {
+    name: &'static str,
+    kind: u32,
+    flags: u64,
+    address: u64,
+    offset: u64,
+    size: u64,
+    alignment: u64,
+}
+
+pub fn executable(asm: &str) -> Result<Vec<u8>, String> {
+    let mut program = assembler::assemble(asm)?;
+
+    let text_offset = (HEADER_SIZE + PROGRAM_HEADERS * PROGRAM_HEADER_SIZE).next_multiple_of(16);
+    let rodata_offset = (text_offset + program.text.len() as u64).next_multiple_of(16);
+    let code_end = rodata_offset + program.rodata.len() as u64;
+
+    // Offsets and addresses of a segment have to be the same within a page
+    let data_offset = code_end.next_multiple_of(PAGE_SIZE);
+    let data_address = BASE_ADDRESS + data_offset;
+    let bss_address = (data_address + program.data.len() as u64).next_multiple_of(16);
+    let data_end = data_offset + program.data.len() as u64;
+
+    let address = |section| match section {
+        Section::Text => BASE_ADDRESS + text_offset,
+        Section::Rodata => BASE_ADDRESS + rodata_offset,
+        Section::Data => data_address,
+        Section::Bss => bss_address,
+    };
+    let entry = program.link(address, "_start")?;
+
+    let names_offset = data_end;
+    let section_headers_offset = (names_offset + SECTION_NAMES.len() as u64).next_multiple_of(8);
+    let sections = [
+        SectionHeader {
+            name: ".text",
+            kind: SHT_PROGBITS,
+            flags: SHF_ALLOC | SHF_EXECINSTR,
+            address: address(Section::Text),
+            offset: text_offset,
+            size: program.text.len() as u64,
+            alignment: 16,
+        },
+        SectionHeader {
+            name: ".rodata",
+            kind: SHT_PROGBITS,
+            flags: SHF_ALLOC,
+            address: address(Section::Rodata),
+            offset: rodata_offset,
+            size: program.rodata.len() as u64,
+            alignment: 16,
+        },
+        SectionHeader {
+            name: ".data",
+            kind: SHT_PROGBITS,
+            flags: SHF_ALLOC | SHF_WRITE,
+            address: data_address,
+            offset: data_offset,
+            size: program.data.len() as u64,
+            alignment: 16,
+        },
+        SectionHeader {
+            name: ".bss",
+            kind: SHT_NOBITS,
+            flags: SHF_ALLOC | SHF_WRITE,
+            address: bss_address,
+            offset: data_end,
+            size: program.bss_size,
+            alignment: 16,
+        },
+        SectionHeader {
+            name: ".shstrtab",
+            kind: SHT_STRTAB,
+            flags: 0,
+            address: 0,
+            offset: names_offset,
+            size: SECTION_NAMES.len() as u64,
+            alignment: 1,
+        },
+    ];
+
+    let mut file = vec![];
+
+    // ELF header: 64-bit, little endian, current version, System V
+    file.extend(b"\x7fELF\x02\x01\x01\x00");
+    file.extend([0; 8]);
+    file.extend(2u16.to_le_bytes()); // executable
+    file.extend(62u16.to_le_bytes()); // x86_64
+    file.extend(1u32.to_le_bytes());
+    file.extend(entry.to_le_bytes());
+    file.extend(HEADER_SIZE.to_le_bytes());
+    file.extend(section_headers_offset.to_le_bytes());
+    file.extend(0u32.to_le_bytes());
+    file.extend((HEADER_SIZE as u16).to_le_bytes());
+    file.extend((PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    file.extend((PROGRAM_HEADERS as u16).to_le_bytes());
+    file.extend((SECTION_HEADER_SIZE as u16).to_le_bytes());
+    // The null section comes first and the names last
+    file.extend((sections.len() as u16 + 1).to_le_bytes());
+    file.extend((sections.len() as u16).to_le_bytes());
+
+    program_header(
+        &mut file,
+        PT_LOAD,
+        PF_R | PF_X,
+        0,
+        BASE_ADDRESS,
+        code_end,
+        code_end,
+    );
+    program_header(
+        &mut file,
+        PT_LOAD,
+        PF_R | PF_W,
+        data_offset,
+        data_address,
+        program.data.len() as u64,
+        bss_address + program.bss_size - data_address,
+    );
+    program_header(&mut file, PT_GNU_STACK, PF_R | PF_W, 0, 0, 0, 0);
+
+    file.resize(text_offset as usize, 0);
+    file.extend(&program.text);
+    file.resize(rodata_offset as usize, 0);
+    file.extend(&program.rodata);
+    file.resize(data_offset as usize, 0);
+    file.extend(&program.data);
+    file.extend(SECTION_NAMES);
+    file.resize(section_headers_offset as usize, 0);
+
+    file.extend([0; SECTION_HEADER_SIZE as usize]);
+    for section in sections {
+        section_header(&mut file, &section);
+    }
+
+    Ok(file)
+}
+
+fn program_header(
+    file: &mut Vec<u8>,
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    address: u64,
+    file_size: u64,
+    memory_size: u64,
+) {
+    let alignment = if kind == PT_LOAD { PAGE_SIZE } else { 16 };
+
+    file.extend(kind.to_le_bytes());
+    file.extend(flags.to_le_bytes());
+    file.extend(offset.to_le_bytes());
+    // Virtual and physical address
+    file.extend(address.to_le_bytes());
+    file.extend(address.to_le_bytes());
+    file.extend(file_size.to_le_bytes());
+    file.extend(memory_size.to_le_bytes());
+    file.extend(alignment.to_le_bytes());
+}
+
+fn section_header(file: &mut Vec<u8>, section: &SectionHeader) {
+    let name = format!("\0{}\0", section.name);
+    let name = SECTION_NAMES
+        .windows(name.len())
+        .position(|window| window == name.as_bytes())
+        .unwrap() as u32
+        + 1;
+
+    file.extend(name.to_le_bytes());
+    file.extend(section.kind.to_le_bytes());
+    file.extend(section.flags.to_le_bytes());
+    file.extend(section.address.to_le_bytes());
+    file.extend(section.offset.to_le_bytes());
+    file.extend(section.size.to_le_bytes());
+    // No linked section or extra information
+    file.extend(0u32.to_le_bytes());
+    file.extend(0u32.to_le_bytes());
+    file.extend(section.alignment.to_le_bytes());
+    // No fixed size entries
+    file.extend(0u64.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cli::{CellOverflow, EofMode, InputMode, TapeMode},
+        codegen::{codegen, AssemblyCodeGenerator, CodegenOptions},
+        lexer::Lexer,
+        parser::Parser,
+    };
+
+    fn u16_at(file: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(file[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u64_at(file: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(file[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn options(tape_size: usize) -> CodegenOptions {
+        CodegenOptions {
+            optimized: true,
+            tape_size,
+            tape: TapeMode::Fixed,
+            cell_bits: 8,
+            cell_overflow: CellOverflow::Wrap,
+            cell_signed: false,
+            eof: EofMode::Zero,
+            input_mode: InputMode::Raw,
+            bounds_check: true,
+            guard_pages: false,
+            dump_tape: false,
+            no_libc: true,
+            main_symbol: "main".to_string(),
+            baked_input: None,
+            evaluated: false,
+            source_path: "cat.bf".to_string(),
+        }
+    }
+
+    fn runtime(options: &CodegenOptions) -> String {
+        let ast = Parser::parse(Lexer::new(",[.>,]".to_string())).unwrap();
+
+        codegen::<AssemblyCodeGenerator>(ast, options)
+    }
+
+    #[test]
+    fn headers() {
+        let file = executable(
+            "	.globl	_start
+	.text
+        ret
+_start:
+        movl    $60, %eax
+        syscall
+	.data
+        .byte   1
+	.bss
+        .lcomm  buffer, 0x2000",
+        )
+        .unwrap();
+
+        assert_eq!(&file[..8], b"\x7fELF\x02\x01\x01\x00");
+        // One byte into .text, which starts after the program headers
+        let text = (HEADER_SIZE + PROGRAM_HEADERS * PROGRAM_HEADER_SIZE).next_multiple_of(16);
+        assert_eq!(u64_at(&file, 24), BASE_ADDRESS + text + 1);
+        assert_eq!(
+            file[text as usize..text as usize + 8],
+            [0xc3, 0xc7, 0xc0, 60, 0, 0, 0, 0x0f]
+        );
+
+        // The data segment is on the next page with room for .bss
+        let data = HEADER_SIZE as usize + PROGRAM_HEADER_SIZE as usize;
+        assert_eq!(u64_at(&file, data + 8), PAGE_SIZE);
+        assert_eq!(u64_at(&file, data + 16), BASE_ADDRESS + PAGE_SIZE);
+        assert_eq!(u64_at(&file, data + 32), 1);
+        assert_eq!(u64_at(&file, data + 40), 0x2010);
+        assert_eq!(file[PAGE_SIZE as usize], 1);
+
+        // Section headers are last, the names are the last section
+        let sections = u64_at(&file, 40) as usize;
+        assert_eq!(u16_at(&file, 60), 6);
+        assert_eq!(u16_at(&file, 62), 5);
+        assert_eq!(file.len(), sections + 6 * SECTION_HEADER_SIZE as usize);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(executable("        ret").unwrap_err().contains("_start"));
+        assert!(executable("_start:\n        frobq   %rax")
+            .unwrap_err()
+            .starts_with("line 2:"));
+    }
+
+    // The whole --no-libc runtime, which only uses what the assembler knows
+    #[test]
+    fn generated_program() {
+        assert!(executable(&runtime(&options(30000))).is_ok());
+        // Too large for .bss, the tape is mapped instead
+        assert!(executable(&runtime(&options(2 << 30))).is_ok());
+
+        let growable = CodegenOptions {
+            tape: TapeMode::Growable,
+            dump_tape: true,
+            ..options(30000)
+        };
+        assert!(executable(&runtime(&growable)).is_ok());
+
+        let guarded = CodegenOptions {
+            bounds_check: false,
+            guard_pages: true,
+            cell_bits: 64,
+            cell_signed: true,
+            cell_overflow: CellOverflow::Trap,
+            ..options(30000)
+        };
+        assert!(executable(&runtime(&guarded)).is_ok());
+    }
+}
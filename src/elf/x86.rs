@@ -0,0 +1,662 @@
+// Machine code for the x86_64 instructions the assembly templates use, in
+// AT&T operand order. Jumps, calls and %rip relative operands always take a
+// 32-bit displacement, so every instruction has its final size before any
+// label has an address.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Register {
+    pub number: u8,
+    // In bytes
+    pub size: u8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Base {
+    Register(u8),
+    Rip(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Memory {
+    pub base: Base,
+    // Register and scale
+    pub index: Option<(u8, u8)>,
+    pub displacement: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Register(Register),
+    Immediate(i64),
+    Memory(Memory),
+    Label(String),
+}
+
+pub struct Encoded {
+    pub bytes: Vec<u8>,
+    // A label whose distance from the end of the instruction goes in the 4
+    // bytes at this offset
+    pub fixup: Option<(usize, String)>,
+}
+
+static REGISTERS: [[&str; 4]; 16] = [
+    ["rax", "eax", "ax", "al"],
+    ["rcx", "ecx", "cx", "cl"],
+    ["rdx", "edx", "dx", "dl"],
+    ["rbx", "ebx", "bx", "bl"],
+    ["rsp", "esp", "sp", "spl"],
+    ["rbp", "ebp", "bp", "bpl"],
+    ["rsi", "esi", "si", "sil"],
+    ["rdi", "edi", "di", "dil"],
+    ["r8", "r8d", "r8w", "r8b"],
+    ["r9", "r9d", "r9w", "r9b"],
+    ["r10", "r10d", "r10w", "r10b"],
+    ["r11", "r11d", "r11w", "r11b"],
+    ["r12", "r12d", "r12w", "r12b"],
+    ["r13", "r13d", "r13w", "r13b"],
+    ["r14", "r14d", "r14w", "r14b"],
+    ["r15", "r15d", "r15w", "r15b"],
+];
+
+pub fn register(name: &str) -> Option<Register> {
+    REGISTERS.iter().enumerate().find_map(|(number, names)| {
+        names.iter().zip([8, 4, 2, 1]).find_map(|(register, size)| {
+            (*register == name).then_some(Register {
+                number: number as u8,
+                size,
+            })
+        })
+    })
+}
+
+// The condition codes of jcc and cmovcc
+fn condition(name: &str) -> Option<u8> {
+    let code = match name {
+        "o" => 0x0,
+        "no" => 0x1,
+        "b" => 0x2,
+        "ae" => 0x3,
+        "e" => 0x4,
+        "ne" => 0x5,
+        "be" => 0x6,
+        "a" => 0x7,
+        "s" => 0x8,
+        "ns" => 0x9,
+        "l" => 0xc,
+        "ge" => 0xd,
+        "le" => 0xe,
+        "g" => 0xf,
+        _ => return None,
+    };
+
+    Some(code)
+}
+
+fn suffix_size(suffix: &str) -> Option<u8> {
+    match suffix {
+        "b" => Some(1),
+        "w" => Some(2),
+        "l" => Some(4),
+        "q" => Some(8),
+        _ => None,
+    }
+}
+
+// spl, bpl, sil and dil can only be used with a REX prefix, without one
+// their numbers are ah, ch, dh and bh
+fn needs_rex(register: Register) -> bool {
+    register.size == 1 && (4..8).contains(&register.number)
+}
+
+// Immediates are at most 32 bits, sign extended for 64-bit operands, but
+// narrower ones may be written either signed or unsigned
+fn immediate_fits(value: i64, size: u8) -> bool {
+    if size == 8 {
+        i32::try_from(value).is_ok()
+    } else {
+        let bits = size as u32 * 8;
+        value >= -(1 << (bits - 1)) && value < 1 << bits
+    }
+}
+
+// The immediate as a sign extended byte, if it is one once it's as wide as
+// the operand
+fn short_immediate(value: i64, size: u8) -> Option<i8> {
+    let bits = size as u32 * 8;
+    let value = (value << (64 - bits)) >> (64 - bits);
+
+    i8::try_from(value).ok()
+}
+
+struct Encoder {
+    bytes: Vec<u8>,
+    fixup: Option<(usize, String)>,
+}
+
+impl Encoder {
+    // An instruction with a ModRM byte, `reg` is a register number or the
+    // extension of the opcode
+    fn modrm(&mut self, size: u8, opcode: &[u8], reg: u8, reg_rex: bool, rm: &Operand) {
+        let mut rex = 0;
+        let mut force_rex = reg_rex;
+
+        if size == 8 {
+            rex |= 0b1000;
+        }
+        if reg >= 8 {
+            rex |= 0b100;
+        }
+        match rm {
+            Operand::Register(register) => {
+                if register.number >= 8 {
+                    rex |= 0b1;
+                }
+                force_rex |= needs_rex(*register);
+            }
+            Operand::Memory(memory) => {
+                if let Base::Register(base) = memory.base {
+                    if base >= 8 {
+                        rex |= 0b1;
+                    }
+                }
+                if let Some((index, _)) = memory.index {
+                    if index >= 8 {
+                        rex |= 0b10;
+                    }
+                }
+            }
+            _ => unreachable!(),
+        }
+
+        if size == 2 {
+            self.bytes.push(0x66);
+        }
+        if rex != 0 || force_rex {
+            self.bytes.push(0x40 | rex);
+        }
+        self.bytes.extend(opcode);
+
+        let reg = (reg & 7) << 3;
+        match rm {
+            Operand::Register(register) => self.bytes.push(0xc0 | reg | register.number & 7),
+            Operand::Memory(memory) => self.memory(reg, memory),
+            _ => unreachable!(),
+        }
+    }
+
+    fn memory(&mut self, reg: u8, memory: &Memory) {
+        let base = match &memory.base {
+            Base::Rip(label) => {
+                self.bytes.push(reg | 0b101);
+                self.fixup = Some((self.bytes.len(), label.clone()));
+                self.bytes
+                    .extend(memory.displacement.to_le_bytes().iter().take(4));
+                return;
+            }
+            Base::Register(base) => base & 7,
+        };
+
+        // %rbp and %r13 as the base always need a displacement
+        let displacement = memory.displacement;
+        let mode = if displacement == 0 && base != 0b101 {
+            0b00
+        } else if i8::try_from(displacement).is_ok() {
+            0b01
+        } else {
+            0b10
+        };
+
+        // So does %rsp and %r12 as the base a SIB byte
+        if memory.index.is_some() || base == 0b100 {
+            let (index, scale) = memory.index.unwrap_or((0b100, 1));
+            self.bytes.push(mode << 6 | reg | 0b100);
+            self.bytes
+                .push((scale.trailing_zeros() as u8) << 6 | (index & 7) << 3 | base);
+        } else {
+            self.bytes.push(mode << 6 | reg | base);
+        }
+
+        match mode {
+            0b01 => self.bytes.push(displacement as u8),
+            0b10 => self.bytes.extend((displacement as i32).to_le_bytes()),
+            _ => {}
+        }
+    }
+
+    fn immediate(&mut self, value: i64, size: u8) {
+        let bytes = size.min(4) as usize;
+        self.bytes.extend(&value.to_le_bytes()[..bytes]);
+    }
+
+    fn relative(&mut self, opcode: &[u8], target: &Operand) -> Result<(), String> {
+        let Operand::Label(label) = target else {
+            return Err("expected a label".to_string());
+        };
+
+        self.bytes.extend(opcode);
+        self.fixup = Some((self.bytes.len(), label.clone()));
+        self.bytes.extend([0; 4]);
+        Ok(())
+    }
+
+    // add, sub and cmp, `extension` is the opcode extension with an immediate
+    // and `opcode` the byte sized opcode from a register
+    fn arithmetic(
+        &mut self,
+        extension: u8,
+        opcode: u8,
+        size: u8,
+        operands: &[Operand],
+    ) -> Result<(), String> {
+        let wide = (size != 1) as u8;
+
+        match operands {
+            [Operand::Immediate(value), rm @ (Operand::Register(_) | Operand::Memory(_))] => {
+                if !immediate_fits(*value, size) {
+                    return Err(format!("immediate {value} doesn't fit"));
+                }
+
+                if size == 1 {
+                    self.modrm(size, &[0x80], extension, false, rm);
+                    self.immediate(*value, size);
+                } else if let Some(byte) = short_immediate(*value, size) {
+                    self.modrm(size, &[0x83], extension, false, rm);
+                    self.bytes.push(byte as u8);
+                } else {
+                    self.modrm(size, &[0x81], extension, false, rm);
+                    self.immediate(*value, size);
+                }
+            }
+            [Operand::Register(register), rm @ (Operand::Register(_) | Operand::Memory(_))] => self
+                .modrm(
+                    size,
+                    &[opcode + wide],
+                    register.number,
+                    needs_rex(*register),
+                    rm,
+                ),
+            [rm @ Operand::Memory(_), Operand::Register(register)] => self.modrm(
+                size,
+                &[opcode + 2 + wide],
+                register.number,
+                needs_rex(*register),
+                rm,
+            ),
+            _ => return Err("unsupported operands".to_string()),
+        }
+
+        Ok(())
+    }
+
+    fn mov(&mut self, size: u8, operands: &[Operand]) -> Result<(), String> {
+        let wide = (size != 1) as u8;
+
+        match operands {
+            [Operand::Immediate(value), rm @ (Operand::Register(_) | Operand::Memory(_))] => {
+                if !immediate_fits(*value, size) {
+                    return Err(format!("immediate {value} doesn't fit"));
+                }
+
+                self.modrm(size, &[0xc6 + wide], 0, false, rm);
+                self.immediate(*value, size);
+            }
+            [Operand::Register(register), rm @ (Operand::Register(_) | Operand::Memory(_))] => self
+                .modrm(
+                    size,
+                    &[0x88 + wide],
+                    register.number,
+                    needs_rex(*register),
+                    rm,
+                ),
+            [rm @ Operand::Memory(_), Operand::Register(register)] => self.modrm(
+                size,
+                &[0x8a + wide],
+                register.number,
+                needs_rex(*register),
+                rm,
+            ),
+            _ => return Err("unsupported operands".to_string()),
+        }
+
+        Ok(())
+    }
+
+    // Instructions with a single register or memory operand
+    fn unary(
+        &mut self,
+        opcode: u8,
+        extension: u8,
+        size: u8,
+        operands: &[Operand],
+    ) -> Result<(), String> {
+        match operands {
+            [rm @ (Operand::Register(_) | Operand::Memory(_))] => {
+                self.modrm(size, &[opcode + (size != 1) as u8], extension, false, rm);
+                Ok(())
+            }
+            _ => Err("unsupported operands".to_string()),
+        }
+    }
+
+    // An instruction from a register or memory to a register
+    fn register_from(
+        &mut self,
+        size: u8,
+        opcode: &[u8],
+        operands: &[Operand],
+    ) -> Result<(), String> {
+        match operands {
+            [rm @ (Operand::Register(_) | Operand::Memory(_)), Operand::Register(register)] => {
+                self.modrm(size, opcode, register.number, needs_rex(*register), rm);
+                Ok(())
+            }
+            _ => Err("unsupported operands".to_string()),
+        }
+    }
+
+    fn instruction(&mut self, mnemonic: &str, operands: &[Operand]) -> Result<(), String> {
+        match (mnemonic, operands) {
+            ("ret", []) => self.bytes.push(0xc3),
+            ("leave", []) => self.bytes.push(0xc9),
+            ("syscall", []) => self.bytes.extend([0x0f, 0x05]),
+            ("cltq", []) => self.bytes.extend([0x48, 0x98]),
+            ("cqto", []) => self.bytes.extend([0x48, 0x99]),
+            ("jmp", [target]) => self.relative(&[0xe9], target)?,
+            ("call", [target]) => self.relative(&[0xe8], target)?,
+            ("movabsq", [Operand::Immediate(value), Operand::Register(register)])
+                if register.size == 8 =>
+            {
+                self.bytes.push(0x48 | (register.number >= 8) as u8);
+                self.bytes.push(0xb8 + (register.number & 7));
+                self.bytes.extend(value.to_le_bytes());
+            }
+            ("pushq", [Operand::Register(register)]) | ("popq", [Operand::Register(register)])
+                if register.size == 8 =>
+            {
+                if register.number >= 8 {
+                    self.bytes.push(0x41);
+                }
+                let opcode = if mnemonic == "pushq" { 0x50 } else { 0x58 };
+                self.bytes.push(opcode + (register.number & 7));
+            }
+            ("leaq", [Operand::Memory(_), Operand::Register(_)]) => {
+                self.register_from(8, &[0x8d], operands)?
+            }
+            ("movslq", _) => self.register_from(8, &[0x63], operands)?,
+            _ => return self.sized_instruction(mnemonic, operands),
+        }
+
+        Ok(())
+    }
+
+    // Instructions named by what they do and the size of their operands
+    fn sized_instruction(&mut self, mnemonic: &str, operands: &[Operand]) -> Result<(), String> {
+        if let Some(condition) = mnemonic.strip_prefix('j').and_then(condition) {
+            let [target] = operands else {
+                return Err("expected a label".to_string());
+            };
+            return self.relative(&[0x0f, 0x80 + condition], target);
+        }
+
+        if let Some(condition) = mnemonic.strip_prefix("cmov").and_then(condition) {
+            let size = match operands {
+                [_, Operand::Register(register)] if register.size != 1 => register.size,
+                _ => return Err("unsupported operands".to_string()),
+            };
+            return self.register_from(size, &[0x0f, 0x40 + condition], operands);
+        }
+
+        // Zero and sign extension, sized by both operands
+        for (name, opcode) in [("movz", 0xb6), ("movs", 0xbe)] {
+            let Some(sizes) = mnemonic.strip_prefix(name) else {
+                continue;
+            };
+            let mut sizes = sizes.chars().map(|size| suffix_size(&size.to_string()));
+            let (Some(Some(from)), Some(Some(to)), None) =
+                (sizes.next(), sizes.next(), sizes.next())
+            else {
+                continue;
+            };
+            if from >= to || from > 2 {
+                continue;
+            }
+
+            let opcode = opcode + (from == 2) as u8;
+            return self.register_from(to, &[0x0f, opcode], operands);
+        }
+
+        let (name, size) = mnemonic.split_at(mnemonic.len().saturating_sub(1));
+        let size = suffix_size(size).ok_or_else(|| format!("unknown instruction {mnemonic}"))?;
+
+        for operand in operands {
+            if let Operand::Register(register) = operand {
+                if register.size != size {
+                    return Err(format!(
+                        "{mnemonic} on a register of {} bytes",
+                        register.size
+                    ));
+                }
+            }
+        }
+
+        match name {
+            "add" => self.arithmetic(0, 0x00, size, operands),
+            "sub" => self.arithmetic(5, 0x28, size, operands),
+            "cmp" => self.arithmetic(7, 0x38, size, operands),
+            "mov" => self.mov(size, operands),
+            "test" => match operands {
+                [Operand::Register(register), rm] => {
+                    self.modrm(
+                        size,
+                        &[0x84 + (size != 1) as u8],
+                        register.number,
+                        needs_rex(*register),
+                        rm,
+                    );
+                    Ok(())
+                }
+                _ => Err("unsupported operands".to_string()),
+            },
+            "inc" => self.unary(0xfe, 0, size, operands),
+            "dec" => self.unary(0xfe, 1, size, operands),
+            "neg" => self.unary(0xf6, 3, size, operands),
+            "div" => self.unary(0xf6, 6, size, operands),
+            "idiv" => self.unary(0xf6, 7, size, operands),
+            "shl" | "shr" | "sar" => {
+                let extension = match name {
+                    "shl" => 4,
+                    "shr" => 5,
+                    _ => 7,
+                };
+
+                match operands {
+                    [Operand::Immediate(count), rm] if (0..64).contains(count) => {
+                        self.unary(0xc0, extension, size, std::slice::from_ref(rm))?;
+                        self.bytes.push(*count as u8);
+                        Ok(())
+                    }
+                    _ => Err("unsupported operands".to_string()),
+                }
+            }
+            _ => Err(format!("unknown instruction {mnemonic}")),
+        }
+    }
+}
+
+pub fn encode(mnemonic: &str, operands: &[Operand]) -> Result<Encoded, String> {
+    let mut encoder = Encoder {
+        bytes: vec![],
+        fixup: None,
+    };
+    encoder.instruction(mnemonic, operands)?;
+
+    Ok(Encoded {
+        bytes: encoder.bytes,
+        fixup: encoder.fixup,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reg(name: &str) -> Operand {
+        Operand::Register(register(name).unwrap())
+    }
+
+    fn mem(base: &str, index: Option<(&str, u8)>, displacement: i64) -> Operand {
+        let number = |name| register(name).unwrap().number;
+
+        Operand::Memory(Memory {
+            base: Base::Register(number(base)),
+            index: index.map(|(name, scale)| (number(name), scale)),
+            displacement,
+        })
+    }
+
+    fn bytes(mnemonic: &str, operands: &[Operand]) -> Vec<u8> {
+        let encoded = encode(mnemonic, operands).unwrap();
+        assert!(encoded.fixup.is_none());
+        encoded.bytes
+    }
+
+    // The same bytes as GNU as, or the long form of the instruction where as
+    // has a shorter one
+    #[test]
+    fn registers_and_immediates() {
+        assert_eq!(bytes("movq", &[reg("rsp"), reg("rbp")]), [0x48, 0x89, 0xe5]);
+        assert_eq!(
+            bytes("subq", &[Operand::Immediate(16), reg("rsp")]),
+            [0x48, 0x83, 0xec, 0x10]
+        );
+        assert_eq!(
+            bytes("cmpq", &[Operand::Immediate(-4095), reg("rax")]),
+            [0x48, 0x81, 0xf8, 0x01, 0xf0, 0xff, 0xff]
+        );
+        assert_eq!(
+            bytes("movabsq", &[Operand::Immediate(0x123456789), reg("rcx")]),
+            [0x48, 0xb9, 0x89, 0x67, 0x45, 0x23, 0x01, 0, 0, 0]
+        );
+        assert_eq!(
+            bytes("movl", &[Operand::Immediate(-1), reg("r8d")]),
+            [0x41, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff]
+        );
+        assert_eq!(
+            bytes("addb", &[Operand::Immediate('0' as i64), reg("r8b")]),
+            [0x41, 0x80, 0xc0, 0x30]
+        );
+        assert_eq!(
+            bytes("cmovs", &[reg("rdx"), reg("r8")]),
+            [0x4c, 0x0f, 0x48, 0xc2]
+        );
+        assert_eq!(
+            bytes("sarq", &[Operand::Immediate(3), reg("r13")]),
+            [0x49, 0xc1, 0xfd, 0x03]
+        );
+        assert_eq!(bytes("idivq", &[reg("rcx")]), [0x48, 0xf7, 0xf9]);
+        assert_eq!(bytes("divq", &[reg("r9")]), [0x49, 0xf7, 0xf1]);
+        assert_eq!(bytes("negq", &[reg("r8")]), [0x49, 0xf7, 0xd8]);
+        assert_eq!(bytes("pushq", &[reg("r12")]), [0x41, 0x54]);
+        assert_eq!(bytes("popq", &[reg("rbx")]), [0x5b]);
+        assert_eq!(bytes("cqto", &[]), [0x48, 0x99]);
+        assert_eq!(bytes("cltq", &[]), [0x48, 0x98]);
+        assert_eq!(bytes("syscall", &[]), [0x0f, 0x05]);
+        assert_eq!(bytes("leave", &[]), [0xc9]);
+        assert_eq!(bytes("ret", &[]), [0xc3]);
+    }
+
+    #[test]
+    fn memory() {
+        assert_eq!(
+            bytes("movq", &[mem("rbp", None, -8), reg("rax")]),
+            [0x48, 0x8b, 0x45, 0xf8]
+        );
+        // %r12 always needs a SIB byte and %r13 a displacement
+        assert_eq!(
+            bytes("movq", &[mem("r12", None, 0), reg("rax")]),
+            [0x49, 0x8b, 0x04, 0x24]
+        );
+        assert_eq!(
+            bytes("movq", &[mem("r13", None, 0), reg("rax")]),
+            [0x49, 0x8b, 0x45, 0x00]
+        );
+        assert_eq!(
+            bytes("movzbl", &[mem("rcx", Some(("rax", 1)), 0), reg("edx")]),
+            [0x0f, 0xb6, 0x14, 0x01]
+        );
+        assert_eq!(
+            bytes(
+                "leaq",
+                &[mem("rax", Some(("rbx", 1)), 0x10000000), reg("rcx")]
+            ),
+            [0x48, 0x8d, 0x8c, 0x18, 0x00, 0x00, 0x00, 0x10]
+        );
+        assert_eq!(
+            bytes(
+                "addq",
+                &[Operand::Immediate(1), mem("rax", Some(("rcx", 8)), 8)]
+            ),
+            [0x48, 0x83, 0x44, 0xc8, 0x08, 0x01]
+        );
+        assert_eq!(
+            bytes("movq", &[Operand::Immediate(0), mem("rsp", None, 8)]),
+            [0x48, 0xc7, 0x44, 0x24, 0x08, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            bytes("subw", &[Operand::Immediate(300), mem("rax", None, 0)]),
+            [0x66, 0x81, 0x28, 0x2c, 0x01]
+        );
+        assert_eq!(bytes("incq", &[mem("rdi", None, 0)]), [0x48, 0xff, 0x07]);
+    }
+
+    // %dil is %bh without a REX prefix
+    #[test]
+    fn byte_registers() {
+        assert_eq!(
+            bytes("movb", &[reg("dil"), mem("rcx", Some(("rax", 1)), 0)]),
+            [0x40, 0x88, 0x3c, 0x01]
+        );
+        assert_eq!(
+            bytes(
+                "cmpb",
+                &[Operand::Immediate(0), mem("rsi", Some(("rdx", 1)), 0)]
+            ),
+            [0x80, 0x3c, 0x16, 0x00]
+        );
+    }
+
+    #[test]
+    fn fixups() {
+        let label = || Operand::Label("target".to_string());
+
+        let jump = encode("jmp", &[label()]).unwrap();
+        assert_eq!(jump.bytes, [0xe9, 0, 0, 0, 0]);
+        assert_eq!(jump.fixup, Some((1, "target".to_string())));
+
+        let branch = encode("jae", &[label()]).unwrap();
+        assert_eq!(branch.bytes, [0x0f, 0x83, 0, 0, 0, 0]);
+        assert_eq!(branch.fixup, Some((2, "target".to_string())));
+
+        let call = encode("call", &[label()]).unwrap();
+        assert_eq!(call.bytes, [0xe8, 0, 0, 0, 0]);
+        assert_eq!(call.fixup, Some((1, "target".to_string())));
+
+        let rip = Operand::Memory(Memory {
+            base: Base::Rip("target".to_string()),
+            index: None,
+            displacement: 0,
+        });
+        let load = encode("leaq", &[rip, reg("rdi")]).unwrap();
+        assert_eq!(load.bytes, [0x48, 0x8d, 0x3d, 0, 0, 0, 0]);
+        assert_eq!(load.fixup, Some((3, "target".to_string())));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(encode("frobq", &[reg("rax")]).is_err());
+        assert!(encode("movq", &[reg("eax"), reg("rbx")]).is_err());
+        assert!(encode("addq", &[Operand::Immediate(1 << 40), reg("rax")]).is_err());
+        assert!(encode("addb", &[Operand::Immediate(256), reg("al")]).is_err());
+        assert!(encode("shlq", &[Operand::Immediate(64), reg("rax")]).is_err());
+    }
+}
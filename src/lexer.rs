@@ -1,10 +1,11 @@
-use crate::token::Token;
+use crate::{span::Position, token::Token};
 
 #[derive(Debug)]
 pub struct Lexer {
     i: usize,
     data: Vec<char>,
 
+    current_line: usize,
     current_column: usize,
 
     has_eof: bool,
@@ -12,19 +13,30 @@ pub struct Lexer {
 
 impl Lexer {
     pub fn new(source: String) -> Self {
-        let data = source.chars().into_iter().collect();
+        let data = source.chars().collect();
 
         Self {
             i: 0,
             data,
-            current_column: 0,
+            current_line: 1,
+            current_column: 1,
             has_eof: false,
         }
     }
 
     fn advance(&mut self) {
+        if self.current_as_char() == Some('\n') {
+            self.current_line += 1;
+            self.current_column = 1;
+        } else {
+            self.current_column += 1;
+        }
+
         self.i += 1;
-        self.current_column += 1;
+    }
+
+    fn current_position(&self) -> Position {
+        Position::new(self.current_line, self.current_column)
     }
 
     fn current_as_char(&mut self) -> Option<char> {
@@ -44,38 +56,41 @@ impl Lexer {
         }
     }
 
-    fn collect_token(&mut self) -> Option<Token> {
+    fn collect_token(&mut self) -> Option<(Token, Position)> {
         let c = self.current_as_char()?;
+        let position = self.current_position();
         self.advance();
 
-        match c {
-            '>' => Some(Token::Right),
-            '<' => Some(Token::Left),
-            '+' => Some(Token::Add),
-            '-' => Some(Token::Subtract),
-            '.' => Some(Token::PrintChar),
-            ',' => Some(Token::GetChar),
-            '[' => Some(Token::StartLoop),
-            ']' => Some(Token::EndLoop),
-            _ => None,
-        }
+        let token = match c {
+            '>' => Token::Right,
+            '<' => Token::Left,
+            '+' => Token::Add,
+            '-' => Token::Subtract,
+            '.' => Token::PrintChar,
+            ',' => Token::GetChar,
+            '[' => Token::StartLoop,
+            ']' => Token::EndLoop,
+            _ => return None,
+        };
+
+        Some((token, position))
     }
 
-    fn eof(&mut self) -> Option<Token> {
+    fn eof(&mut self) -> Option<(Token, Position)> {
         if self.has_eof {
             None
         } else {
             self.has_eof = true;
-            Some(Token::EOF)
+            Some((Token::EOF, self.current_position()))
         }
     }
 }
 
 impl Iterator for Lexer {
-    type Item = Token;
+    type Item = (Token, Position);
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let None = self.skip_trash() {
+        if self.skip_trash().is_none() {
             return self.eof();
         }
 
@@ -1,23 +1,35 @@
 mod ast;
+mod bytecode;
 mod cli;
 mod codegen;
+mod coverage;
+mod debugger;
+mod elf;
 mod lexer;
 mod parser;
+mod profiler;
+mod repl;
+mod run;
+mod snapshot;
+mod span;
+mod tape_view;
 mod token;
+mod trace;
 
 use ast::AST;
-use cli::Cli;
+use cli::{Cli, Command as CliCommand, MachineArgs, UNBOUNDED_CELL_BITS};
 use lexer::Lexer;
 use parser::Parser;
 
-use codegen::{codegen, AssemblyCodeGenerator, CCodeGenerator};
+use codegen::{codegen, AssemblyCodeGenerator, CCodeGenerator, CodegenOptions};
 
 use clap::Parser as CliParser;
 use std::{
     ffi::OsStr,
     fs,
+    os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
-    process::Command,
+    process::{self, Command},
     time::SystemTime,
 };
 
@@ -78,43 +90,191 @@ impl CompilationPaths {
 fn main() {
     let cli = Cli::parse();
 
-    if cli.assembly && cli.c {
-        panic!("Can't output both c and asm at the same time!");
+    match &cli.command {
+        Some(CliCommand::Run(args)) => return run::run(args),
+        Some(CliCommand::Repl(args)) => return repl::run(args),
+        Some(CliCommand::Debug(args)) => return debugger::run(args),
+        None => {}
+    }
+
+    if [cli.assembly, cli.c, cli.bytecode]
+        .iter()
+        .filter(|output| **output)
+        .count()
+        > 1
+    {
+        panic!("Can only output one of c, asm and bytecode at the same time!");
     }
 
     let output_path = if let Some(path) = &cli.output_path {
         path.clone()
     } else {
-        match (cli.assembly, cli.c) {
-            (false, false) => "a.out",
-            (true, false) => "a.S",
-            (false, true) => "a.c",
-            (true, true) => unreachable!(),
+        match (cli.assembly, cli.c, cli.bytecode, cli.object) {
+            (false, false, false, false) => "a.out",
+            (true, false, false, false) => "a.S",
+            (false, true, false, false) => "a.c",
+            (false, false, true, false) => "a.bfc",
+            (false, false, false, true) => "a.o",
+            _ => unreachable!(),
         }
         .to_string()
     };
 
-    let compilation_paths = CompilationPaths::new(&cli.input_path, &output_path, !cli.keep_files);
+    let input_path = cli
+        .input_path
+        .as_deref()
+        .expect("clap requires an input path without a subcommand");
+    let compilation_paths = CompilationPaths::new(input_path, &output_path, !cli.keep_files);
 
-    let source = fs::read_to_string(&compilation_paths.source_path).expect(&format!(
-        "Failed to read source {}",
-        compilation_paths.source_path.to_str().unwrap()
-    ));
+    let source = fs::read_to_string(&compilation_paths.source_path).unwrap_or_else(|_| {
+        panic!(
+            "Failed to read source {}",
+            compilation_paths.source_path.to_str().unwrap()
+        )
+    });
 
-    let lexer = Lexer::new(source);
-    let ast = Parser::parse(lexer);
+    let ast = parse_source(input_path, source);
 
     if cli.dump_ast {
         println!("{:#?}", ast);
         return;
     }
 
-    if cli.assembly {
-        handle_asm(ast, &compilation_paths, &cli);
+    if stop_at_bytecode(&compilation_paths.output_path, &cli) {
+        handle_bytecode(ast, &compilation_paths, &cli);
+        return;
+    }
+
+    let object = stop_at_object(&compilation_paths.output_path, &cli);
+
+    // -c conflicts with these, but an output path ending in .o doesn't
+    if object {
+        let conflict = if cli.c {
+            Some(("-C", "object files come from the assembly backend"))
+        } else if cli.elf {
+            Some(("--elf", "the program is its own entry point"))
+        } else if cli.no_libc {
+            Some(("--no-libc", "the program is its own entry point"))
+        } else {
+            None
+        };
+
+        if let Some((flag, reason)) = conflict {
+            eprintln!("{flag} can't output an object file, {reason}");
+            process::exit(1);
+        }
+    }
+
+    let options = CodegenOptions {
+        bounds_check: cli.bounds_check,
+        guard_pages: cli.guard_pages,
+        dump_tape: cli.dump_tape_on_exit,
+        no_libc: cli.no_libc || cli.elf,
+        main_symbol: if object {
+            cli.symbol.clone()
+        } else {
+            "main".to_string()
+        },
+        baked_input: cli
+            .bake_input
+            .as_ref()
+            .map(|path| fs::read(path).unwrap_or_else(|_| panic!("Failed to read input {path}"))),
+        source_path: input_path.to_owned(),
+        ..codegen_options(cli.optimizations, &cli.machine)
+    };
+
+    if options.cell_bits == UNBOUNDED_CELL_BITS {
+        if cli.assembly {
+            eprintln!("The assembly backend doesn't support --cell-bits unbounded, leave out -S to use the C backend");
+            process::exit(1);
+        }
+
+        if object {
+            eprintln!("Object files come from the assembly backend, which doesn't support --cell-bits unbounded");
+            process::exit(1);
+        }
+
+        if cli.guard_pages {
+            eprintln!("--guard-pages doesn't work with --cell-bits unbounded, the tape is sparse");
+            process::exit(1);
+        }
+
+        if cli.dump_tape_on_exit {
+            eprintln!("--dump-tape-on-exit doesn't support --cell-bits unbounded");
+            process::exit(1);
+        }
+    }
+
+    // The runtime without libc has no arbitrary-precision cells
+    if options.no_libc && options.unbounded() {
+        let flag = if cli.elf { "--elf" } else { "--no-libc" };
+        eprintln!("{flag} doesn't support --cell-bits unbounded, it needs libc");
+        process::exit(1);
+    }
+
+    if cli.elf {
+        handle_elf(ast, &compilation_paths, &options);
+        return;
+    }
+
+    if cli.assembly || cli.no_libc || object {
+        handle_asm(ast, &compilation_paths, &cli, &options);
+        return;
+    }
+
+    // Without gcc the executable is written like with --elf
+    if !stop_at_c(&compilation_paths.output_path, &cli) && !options.unbounded() && !gcc_installed()
+    {
+        println!("`gcc` isn't installed, writing the executable without it");
+        let options = CodegenOptions {
+            no_libc: true,
+            ..options
+        };
+        handle_elf(ast, &compilation_paths, &options);
         return;
     }
 
-    handle_c(ast, &compilation_paths, &cli);
+    handle_c(ast, &compilation_paths, &cli, &options);
+}
+
+fn parse_source(input_path: &str, source: String) -> AST {
+    Parser::parse(Lexer::new(source)).unwrap_or_else(|error| {
+        eprintln!("{input_path}:{error}");
+        process::exit(1);
+    })
+}
+
+fn codegen_options(optimized: bool, machine: &MachineArgs) -> CodegenOptions {
+    CodegenOptions {
+        optimized,
+        tape_size: machine.tape_size,
+        tape: machine.tape,
+        cell_bits: machine.cell_bits,
+        cell_overflow: machine.cell_overflow,
+        cell_signed: machine.cell_signed,
+        eof: machine.eof,
+        input_mode: machine.input_mode,
+        bounds_check: false,
+        guard_pages: false,
+        dump_tape: false,
+        no_libc: false,
+        main_symbol: "main".to_string(),
+        baked_input: None,
+        evaluated: false,
+        source_path: String::new(),
+    }
+}
+
+fn vm_config(machine: &MachineArgs) -> bytecode::Config {
+    bytecode::Config {
+        tape_size: machine.tape_size,
+        tape: machine.tape,
+        cell_bits: machine.cell_bits,
+        cell_overflow: machine.cell_overflow,
+        cell_signed: machine.cell_signed,
+        eof: machine.eof,
+        input_mode: machine.input_mode,
+    }
 }
 
 fn save(output_path: &Path, data: &str) {
@@ -123,24 +283,26 @@ fn save(output_path: &Path, data: &str) {
 
 // ASM
 //
-fn handle_c(ast: AST, compilation_paths: &CompilationPaths, cli: &Cli) {
-    let c = codegen::<CCodeGenerator>(ast, cli.optimizations);
+fn handle_c(ast: AST, compilation_paths: &CompilationPaths, cli: &Cli, options: &CodegenOptions) {
+    let c = codegen::<CCodeGenerator>(ast, options);
 
-    if stop_at_c(&compilation_paths.output_path, &cli) {
+    if stop_at_c(&compilation_paths.output_path, cli) {
         save(&compilation_paths.output_path, &c); // Respect specified output path
         return;
     } else {
         save(&compilation_paths.c_path, &c);
     }
 
-    compile_c(&compilation_paths, cli);
+    compile_c(compilation_paths, cli);
 
     // NOTE: Should the files be removed or should they stay in tmp?
     if !cli.keep_files {
-        fs::remove_file(&compilation_paths.c_path).expect(&format!(
-            "Failed to remove c file {}",
-            compilation_paths.asm_path.to_str().unwrap()
-        ));
+        fs::remove_file(&compilation_paths.c_path).unwrap_or_else(|_| {
+            panic!(
+                "Failed to remove c file {}",
+                compilation_paths.asm_path.to_str().unwrap()
+            )
+        });
     }
 }
 
@@ -157,6 +319,10 @@ fn stop_at_c(output_path: &Path, cli: &Cli) -> bool {
     }
 }
 
+fn gcc_installed() -> bool {
+    Command::new("gcc").arg("--version").output().is_ok()
+}
+
 fn compile_c(paths: &CompilationPaths, cli: &Cli) {
     let c_path = paths.c_path.to_str().unwrap();
     let executable_path = paths.output_path.to_str().unwrap();
@@ -179,36 +345,43 @@ fn compile_c(paths: &CompilationPaths, cli: &Cli) {
             .lines()
             .for_each(|line| eprintln!("{line}"));
 
-        return;
+        process::exit(1);
     }
     println!("SUCCESS");
 }
 
 // ASM
 
-fn handle_asm(ast: AST, compilation_paths: &CompilationPaths, cli: &Cli) {
-    let asm = codegen::<AssemblyCodeGenerator>(ast, cli.optimizations);
+fn handle_asm(ast: AST, compilation_paths: &CompilationPaths, cli: &Cli, options: &CodegenOptions) {
+    let asm = codegen::<AssemblyCodeGenerator>(ast, options);
 
-    if stop_at_asm(&compilation_paths.output_path, &cli) {
+    if stop_at_asm(&compilation_paths.output_path, cli) {
         save(&compilation_paths.output_path, &asm); // Respect specified output path
         return;
     } else {
         save(&compilation_paths.asm_path, &asm);
     }
 
-    compile_asm(&compilation_paths);
+    let object = stop_at_object(&compilation_paths.output_path, cli);
+    compile_asm(compilation_paths, cli.no_libc, object);
 
     // NOTE: Should the files be removed or should they stay in tmp?
     if !cli.keep_files {
-        fs::remove_file(&compilation_paths.asm_path).expect(&format!(
-            "Failed to remove asm file {}",
-            compilation_paths.asm_path.to_str().unwrap()
-        ));
-
-        fs::remove_file(&compilation_paths.object_path).expect(&format!(
-            "Failed to remove object file {}",
-            compilation_paths.object_path.to_str().unwrap()
-        ));
+        fs::remove_file(&compilation_paths.asm_path).unwrap_or_else(|_| {
+            panic!(
+                "Failed to remove asm file {}",
+                compilation_paths.asm_path.to_str().unwrap()
+            )
+        });
+
+        if !object {
+            fs::remove_file(&compilation_paths.object_path).unwrap_or_else(|_| {
+                panic!(
+                    "Failed to remove object file {}",
+                    compilation_paths.object_path.to_str().unwrap()
+                )
+            });
+        }
     }
 }
 
@@ -225,16 +398,44 @@ fn stop_at_asm(output_path: &Path, cli: &Cli) -> bool {
     }
 }
 
-fn compile_asm(paths: &CompilationPaths) {
+fn stop_at_object(output_path: &Path, cli: &Cli) -> bool {
+    if cli.object {
+        true
+    } else {
+        // if output file ends with .o or .O it will count as an object file
+        if let Some(extension) = output_path.extension() {
+            extension.to_ascii_lowercase() == OsStr::new("o")
+        } else {
+            false
+        }
+    }
+}
+
+fn compile_asm(paths: &CompilationPaths, no_libc: bool, object: bool) {
     let asm_path = paths.asm_path.to_str().unwrap();
-    let obj_path = paths.object_path.to_str().unwrap();
+    // With -c the object file is the output
+    let obj_path = if object {
+        &paths.output_path
+    } else {
+        &paths.object_path
+    }
+    .to_str()
+    .unwrap();
     let executable_path = paths.output_path.to_str().unwrap();
 
-    print!("Running `as`... ");
-    let output = Command::new("as")
-        .args(&[asm_path, "-o", obj_path])
+    // The libc runtime has comments after instructions, which `as` doesn't
+    // take, gcc preprocesses .S files first
+    let (assembler, args) = if no_libc {
+        ("as", vec![asm_path, "-o", obj_path])
+    } else {
+        ("gcc", vec!["-c", asm_path, "-o", obj_path])
+    };
+
+    print!("Running `{assembler}`... ");
+    let output = Command::new(assembler)
+        .args(&args)
         .output()
-        .expect("Failed to run `as`. Make sure it's installed.");
+        .unwrap_or_else(|_| panic!("Failed to run `{assembler}`. Make sure it's installed."));
 
     if !output.status.success() {
         println!("FAILED");
@@ -243,15 +444,22 @@ fn compile_asm(paths: &CompilationPaths) {
             .lines()
             .for_each(|line| eprintln!("{line}"));
 
-        return;
+        process::exit(1);
     }
     println!("SUCCESS");
 
-    print!("Running `gcc`... ");
-    let output = Command::new("gcc")
-        .args(&[obj_path, "-o", executable_path])
+    if object {
+        return;
+    }
+
+    // Without libc there is nothing to link but the program itself
+    let linker = if no_libc { "ld" } else { "gcc" };
+
+    print!("Running `{linker}`... ");
+    let output = Command::new(linker)
+        .args([obj_path, "-o", executable_path])
         .output()
-        .expect("Failed to run `gcc`. Make sure it's installed.");
+        .unwrap_or_else(|_| panic!("Failed to run `{linker}`. Make sure it's installed."));
 
     if !output.status.success() {
         println!("FAILED");
@@ -260,7 +468,44 @@ fn compile_asm(paths: &CompilationPaths) {
             .lines()
             .for_each(|line| eprintln!("{line}"));
 
-        return;
+        process::exit(1);
     }
     println!("SUCCESS");
 }
+
+// ELF
+
+fn handle_elf(ast: AST, compilation_paths: &CompilationPaths, options: &CodegenOptions) {
+    let asm = codegen::<AssemblyCodeGenerator>(ast, options);
+    let executable = elf::executable(&asm).unwrap_or_else(|error| {
+        eprintln!("Failed to write the executable: {error}");
+        process::exit(1);
+    });
+
+    let output_path = &compilation_paths.output_path;
+    fs::write(output_path, executable).expect("Failed to write executable");
+    fs::set_permissions(output_path, fs::Permissions::from_mode(0o755))
+        .expect("Failed to make the executable executable");
+}
+
+// Bytecode
+
+fn handle_bytecode(ast: AST, compilation_paths: &CompilationPaths, cli: &Cli) {
+    let program = bytecode::compile(&ast, &codegen_options(cli.optimizations, &cli.machine));
+
+    fs::write(&compilation_paths.output_path, program.to_bytes())
+        .expect("Failed to write bytecode file");
+}
+
+fn stop_at_bytecode(output_path: &Path, cli: &Cli) -> bool {
+    if cli.bytecode {
+        true
+    } else {
+        // if output file ends with .bfc it will count as bytecode
+        if let Some(extension) = output_path.extension() {
+            extension.to_ascii_lowercase() == OsStr::new("bfc")
+        } else {
+            false
+        }
+    }
+}
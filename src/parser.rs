@@ -1,19 +1,43 @@
-use std::iter::Peekable;
+use std::{fmt, iter::Peekable};
+
+use crate::{
+    ast::AST,
+    lexer::Lexer,
+    span::{Position, Span},
+    token::Token,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    // Position of the `]`
+    UnexpectedEndLoop(Position),
+    // Position of the `[` that was never closed
+    UnclosedLoop(Position),
+}
 
-use crate::{ast::AST, lexer::Lexer, token::Token};
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedEndLoop(position) => write!(f, "{position}: unexpected `]`"),
+            ParseError::UnclosedLoop(position) => write!(f, "{position}: unclosed `[`"),
+        }
+    }
+}
 
 pub struct Parser {
     lexer: Peekable<Lexer>,
     current: Token,
+    position: Position,
 }
 
 impl Parser {
-    pub fn parse(mut lexer: Lexer) -> AST {
-        let token = lexer.next().expect("Ran out of tokens");
+    pub fn parse(mut lexer: Lexer) -> Result<AST, ParseError> {
+        let (token, position) = lexer.next().expect("Ran out of tokens");
 
         let mut parser = Parser {
             lexer: lexer.peekable(),
             current: token,
+            position,
         };
 
         parser.parse_root()
@@ -21,7 +45,7 @@ impl Parser {
 
     fn advance(&mut self) -> Token {
         let current = self.current.clone();
-        self.current = self.lexer.next().expect("Ran out of tokens");
+        (self.current, self.position) = self.lexer.next().expect("Ran out of tokens");
         current
     }
 
@@ -35,94 +59,102 @@ impl Parser {
         }
     }
 
-    pub fn parse_root(&mut self) -> AST {
+    pub fn parse_root(&mut self) -> Result<AST, ParseError> {
         let mut statments = vec![];
 
         while self.current != Token::EOF {
             match self.current {
                 Token::Add => {
-                    statments.push(AST::Add(self.capture_number_of_occurances(Token::Add)));
+                    let (times, span) = self.capture_number_of_occurances(Token::Add);
+                    statments.push(AST::Add(times, span));
                     continue;
                 }
                 Token::Subtract => {
-                    statments.push(AST::Subtract(
-                        self.capture_number_of_occurances(Token::Subtract),
-                    ));
+                    let (times, span) = self.capture_number_of_occurances(Token::Subtract);
+                    statments.push(AST::Subtract(times, span));
                     continue;
                 }
                 Token::Right => {
-                    statments.push(AST::Right(self.capture_number_of_occurances(Token::Right)));
+                    let (times, span) = self.capture_number_of_occurances(Token::Right);
+                    statments.push(AST::Right(times, span));
                     continue;
                 }
                 Token::Left => {
-                    statments.push(AST::Left(self.capture_number_of_occurances(Token::Left)));
+                    let (times, span) = self.capture_number_of_occurances(Token::Left);
+                    statments.push(AST::Left(times, span));
                     continue;
                 }
-                Token::GetChar => statments.push(AST::GetChar),
-                Token::PrintChar => statments.push(AST::PrintChar),
+                Token::GetChar => statments.push(AST::GetChar(Span::at(self.position))),
+                Token::PrintChar => statments.push(AST::PrintChar(Span::at(self.position))),
                 Token::StartLoop => {
-                    statments.push(self.parse_loop());
+                    statments.push(self.parse_loop()?);
                     continue;
                 }
-                Token::EndLoop => panic!("Unexpected end loop"),
+                Token::EndLoop => return Err(ParseError::UnexpectedEndLoop(self.position)),
                 Token::EOF => break,
             }
 
             self.advance();
         }
 
-        AST::Root(statments)
+        Ok(AST::Root(statments))
     }
 
-    pub fn capture_number_of_occurances(&mut self, collecting: Token) -> usize {
+    pub fn capture_number_of_occurances(&mut self, collecting: Token) -> (usize, Span) {
         let mut amount = 0;
+        let mut span = Span::at(self.position);
 
         while self.current == collecting {
             amount += 1;
+            span.end = self.position;
             self.advance();
         }
 
-        return amount;
+        (amount, span)
     }
 
-    pub fn parse_loop(&mut self) -> AST {
+    pub fn parse_loop(&mut self) -> Result<AST, ParseError> {
+        let start = self.position;
         self.eat(Token::StartLoop);
         let mut statments = vec![];
 
         while self.current != Token::EndLoop {
             match self.current {
                 Token::Add => {
-                    statments.push(AST::Add(self.capture_number_of_occurances(Token::Add)));
+                    let (times, span) = self.capture_number_of_occurances(Token::Add);
+                    statments.push(AST::Add(times, span));
                     continue;
                 }
                 Token::Subtract => {
-                    statments.push(AST::Subtract(
-                        self.capture_number_of_occurances(Token::Subtract),
-                    ));
+                    let (times, span) = self.capture_number_of_occurances(Token::Subtract);
+                    statments.push(AST::Subtract(times, span));
                     continue;
                 }
                 Token::Right => {
-                    statments.push(AST::Right(self.capture_number_of_occurances(Token::Right)));
+                    let (times, span) = self.capture_number_of_occurances(Token::Right);
+                    statments.push(AST::Right(times, span));
                     continue;
                 }
                 Token::Left => {
-                    statments.push(AST::Left(self.capture_number_of_occurances(Token::Left)));
+                    let (times, span) = self.capture_number_of_occurances(Token::Left);
+                    statments.push(AST::Left(times, span));
                     continue;
                 }
-                Token::GetChar => statments.push(AST::GetChar),
-                Token::PrintChar => statments.push(AST::PrintChar),
+                Token::GetChar => statments.push(AST::GetChar(Span::at(self.position))),
+                Token::PrintChar => statments.push(AST::PrintChar(Span::at(self.position))),
                 Token::StartLoop => {
-                    statments.push(self.parse_loop());
+                    statments.push(self.parse_loop()?);
                     continue;
                 }
                 Token::EndLoop => panic!("Unreachable"),
-                Token::EOF => panic!("Unexpected EOF"),
+                Token::EOF => return Err(ParseError::UnclosedLoop(start)),
             }
 
             self.advance();
         }
 
+        let end = self.position;
         self.eat(Token::EndLoop);
-        AST::Loop(statments)
+        Ok(AST::Loop(statments, Span::new(start, end)))
     }
 }
@@ -0,0 +1,268 @@
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bytecode::{Observer, Op, Program, Vm},
+    span::Span,
+};
+
+// Longest source snippet shown for a loop
+const SNIPPET_LENGTH: usize = 40;
+
+#[derive(Default, Clone)]
+struct LoopStats {
+    entries: u64,
+    iterations: u64,
+    steps: u64,
+    time: Duration,
+}
+
+pub struct Profiler<'p> {
+    program: &'p Program,
+    counts: Vec<u64>,
+    // Indexed by the index of the loop's `JumpIfZero`
+    loops: Vec<LoopStats>,
+    // Loops that are currently running, with their entry time and step
+    stack: Vec<(usize, Instant, u64)>,
+    started: Instant,
+}
+
+impl Observer for Profiler<'_> {
+    fn before(&mut self, _vm: &Vm, pc: usize) {
+        self.counts[pc] += 1;
+    }
+
+    fn after(&mut self, vm: &Vm, pc: usize) {
+        match self.program.ops[pc] {
+            Op::JumpIfZero(_) if vm.pc() == pc + 1 => {
+                self.loops[pc].entries += 1;
+                self.loops[pc].iterations += 1;
+                self.stack.push((pc, Instant::now(), vm.steps() - 1));
+            }
+            Op::JumpIfNonZero(target) if vm.pc() == target as usize => {
+                self.loops[target as usize - 1].iterations += 1;
+            }
+            Op::JumpIfNonZero(_) => self.exit_loop(vm.steps()),
+            _ => {}
+        }
+    }
+}
+
+impl<'p> Profiler<'p> {
+    pub fn new(program: &'p Program) -> Self {
+        Self {
+            program,
+            counts: vec![0; program.ops.len()],
+            loops: vec![LoopStats::default(); program.ops.len()],
+            stack: vec![],
+            started: Instant::now(),
+        }
+    }
+
+    fn exit_loop(&mut self, steps: u64) {
+        if let Some((start, entered, entry_steps)) = self.stack.pop() {
+            let stats = &mut self.loops[start];
+            stats.time += entered.elapsed();
+            stats.steps += steps - entry_steps;
+        }
+    }
+
+    // Closes loops that were still running when the program stopped
+    pub fn finish(&mut self, vm: &Vm) {
+        while !self.stack.is_empty() {
+            self.exit_loop(vm.steps());
+        }
+    }
+
+    pub fn report(&self, source: Option<&str>, top: usize) -> String {
+        let total = self.started.elapsed();
+        let steps: u64 = self.counts.iter().sum();
+        let mut report = format!("profile: {steps} steps in {:.3}s\n", total.as_secs_f64());
+
+        let mut loops: Vec<(usize, &LoopStats)> = self
+            .loops
+            .iter()
+            .enumerate()
+            .filter(|(_, stats)| stats.entries > 0)
+            .collect();
+        loops.sort_by_key(|(_, stats)| Reverse(stats.time));
+
+        report += "\nhottest loops:\n";
+        report += &format!(
+            "{:>10} {:>7} {:>12} {:>10} {:>12}  {:<12} source\n",
+            "time", "%", "steps", "entries", "iterations", "position"
+        );
+        for (start, stats) in loops.into_iter().take(top) {
+            let span = self.program.spans[start].to(self.program.spans[self.loop_end(start)]);
+            let percent = 100.0 * stats.time.as_secs_f64() / total.as_secs_f64().max(f64::EPSILON);
+
+            report += &format!(
+                "{:>9.3}s {:>6.1}% {:>12} {:>10} {:>12}  {:<12} {}\n",
+                stats.time.as_secs_f64(),
+                percent,
+                stats.steps,
+                stats.entries,
+                stats.iterations,
+                span.start.to_string(),
+                source.map_or(String::new(), |source| snippet(source, span)),
+            );
+        }
+
+        let mut ops: Vec<usize> = (0..self.counts.len())
+            .filter(|pc| self.counts[*pc] > 0)
+            .collect();
+        ops.sort_by_key(|pc| Reverse(self.counts[*pc]));
+
+        report += "\nhottest ops:\n";
+        report += &format!("{:>12}  {:<12} op\n", "count", "position");
+        for pc in ops.into_iter().take(top) {
+            report += &format!(
+                "{:>12}  {:<12} {:?}\n",
+                self.counts[pc],
+                self.program.spans[pc].start.to_string(),
+                self.program.ops[pc]
+            );
+        }
+
+        report
+    }
+
+    fn loop_end(&self, start: usize) -> usize {
+        match self.program.ops[start] {
+            Op::JumpIfZero(target) => target as usize - 1,
+            _ => unreachable!(),
+        }
+    }
+
+    // The source with the execution count of every line in the margin, in
+    // the style of gcov. Lines without commands get a `-` and lines that
+    // never ran get `#####`.
+    pub fn annotate(&self, source: &str) -> String {
+        let mut lines: BTreeMap<usize, u64> = BTreeMap::new();
+        for (span, count) in self.program.spans.iter().zip(&self.counts) {
+            let line = lines.entry(span.start.line).or_default();
+            *line = (*line).max(*count);
+        }
+
+        source
+            .lines()
+            .enumerate()
+            .map(|(i, text)| {
+                let count = match lines.get(&(i + 1)) {
+                    Some(0) => "#####".to_string(),
+                    Some(count) => count.to_string(),
+                    None => "-".to_string(),
+                };
+                format!("{count:>12}:{:>5}:{text}\n", i + 1)
+            })
+            .collect()
+    }
+}
+
+// The commands within a span, without comments
+fn snippet(source: &str, span: Span) -> String {
+    let commands: String = source
+        .lines()
+        .enumerate()
+        .skip(span.start.line - 1)
+        .take(span.end.line - span.start.line + 1)
+        .flat_map(|(i, text)| {
+            let line = i + 1;
+            text.chars().enumerate().filter(move |(column, _)| {
+                let column = column + 1;
+                (line > span.start.line || column >= span.start.column)
+                    && (line < span.end.line || column <= span.end.column)
+            })
+        })
+        .map(|(_, c)| c)
+        .filter(|c| "><+-.,[]".contains(*c))
+        .collect();
+
+    if commands.chars().count() > SNIPPET_LENGTH {
+        commands
+            .chars()
+            .take(SNIPPET_LENGTH - 3)
+            .collect::<String>()
+            + "..."
+    } else {
+        commands
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bytecode::{compile_source, Limits},
+        span::Position,
+    };
+
+    const SOURCE: &str = "++[->+<]\n\n>.";
+
+    fn profile(source: &str) -> (Program, Vec<u64>, Vec<LoopStats>, String, String) {
+        let (program, config) = compile_source(source, &[]);
+        let mut input: &[u8] = b"";
+        let mut output = vec![];
+
+        let mut profiler = Profiler::new(&program);
+        let mut vm = Vm::new(&program, config, Limits::default(), &mut input, &mut output);
+        vm.run_with(&mut [&mut profiler]).unwrap();
+        profiler.finish(&vm);
+
+        let report = profiler.report(Some(source), 10);
+        let annotated = profiler.annotate(source);
+        let (counts, loops) = (profiler.counts.clone(), profiler.loops.clone());
+
+        (program, counts, loops, report, annotated)
+    }
+
+    #[test]
+    fn counts_ops_and_loops() {
+        let (program, counts, loops, _, _) = profile(SOURCE);
+
+        let start = program
+            .ops
+            .iter()
+            .position(|op| matches!(op, Op::JumpIfZero(_)))
+            .unwrap();
+        assert_eq!(counts[start], 1);
+        assert_eq!(counts[start + 1], 2);
+        assert_eq!(loops[start].entries, 1);
+        assert_eq!(loops[start].iterations, 2);
+        assert_eq!(counts.iter().sum::<u64>(), 1 + 1 + 2 * 5 + 2);
+    }
+
+    #[test]
+    fn report() {
+        let (_, _, _, report, annotated) = profile(SOURCE);
+
+        assert!(report.starts_with("profile: 14 steps in "));
+        let hottest_loop = report.lines().nth(4).unwrap();
+        assert!(
+            hottest_loop.ends_with("1:3          [->+<]"),
+            "{hottest_loop}"
+        );
+
+        assert_eq!(
+            annotated,
+            "           2:    1:++[->+<]\n           -:    2:\n           1:    3:>.\n"
+        );
+    }
+
+    #[test]
+    fn snippets() {
+        let source = "a[b-\n>c]\n";
+        let span = Span::new(Position::new(1, 2), Position::new(2, 3));
+        assert_eq!(snippet(source, span), "[->]");
+
+        let source = "[".to_string() + &"+".repeat(50) + "]";
+        let span = Span::new(Position::new(1, 1), Position::new(1, 52));
+        assert_eq!(
+            snippet(&source, span),
+            "[".to_string() + &"+".repeat(36) + "..."
+        );
+    }
+}
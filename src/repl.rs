@@ -0,0 +1,138 @@
+use std::{
+    fs,
+    io::{self, Write},
+};
+
+use crate::{
+    bytecode::{self, Limits, Program, Vm},
+    cli::ReplArgs,
+    codegen::CodegenOptions,
+    codegen_options,
+    lexer::Lexer,
+    parser::{ParseError, Parser},
+    tape_view::{tape_window, CellFormat},
+    vm_config,
+};
+
+const HELP: &str = "\
+:tape                   show the cells around the pointer
+:reset                  clear the tape and move the pointer back to cell 0
+:load <file>            run a brainfuck file against the tape
+:format <dec|hex|char>  change how cells are displayed
+:help                   show this message
+:quit                   exit the repl";
+
+struct Repl<'a> {
+    vm: Vm<'a>,
+    format: CellFormat,
+    options: CodegenOptions,
+}
+
+pub fn run(args: &ReplArgs) {
+    let mut input = io::stdin();
+    let mut output = io::stdout();
+
+    let mut repl = Repl {
+        vm: Vm::new(
+            &Program::default(),
+            vm_config(&args.machine),
+            Limits::default(),
+            &mut input,
+            &mut output,
+        ),
+        format: CellFormat::Decimal,
+        options: codegen_options(args.optimizations, &args.machine),
+    };
+
+    // Source that is waiting for its brackets to be closed
+    let mut pending = String::new();
+
+    loop {
+        print!("{}", if pending.is_empty() { "bf> " } else { "... " });
+        io::stdout().flush().expect("Failed to write prompt");
+
+        let mut line = String::new();
+        if io::stdin()
+            .read_line(&mut line)
+            .expect("Failed to read line")
+            == 0
+        {
+            println!();
+            return;
+        }
+
+        if pending.is_empty() {
+            if let Some(command) = line.trim().strip_prefix(':') {
+                if !repl.command(command) {
+                    return;
+                }
+                continue;
+            }
+        }
+
+        pending.push_str(&line);
+        match Parser::parse(Lexer::new(pending.clone())) {
+            Ok(ast) => {
+                pending.clear();
+                repl.execute(&bytecode::compile(&ast, &repl.options));
+            }
+            Err(ParseError::UnclosedLoop(_)) => {}
+            Err(error) => {
+                pending.clear();
+                println!("error: {error}");
+            }
+        }
+    }
+}
+
+impl Repl<'_> {
+    fn execute(&mut self, program: &Program) {
+        self.vm.load(program);
+
+        if let Err(error) = self.vm.run() {
+            println!("error: {error}");
+        }
+    }
+
+    // Returns false when the repl should exit
+    fn command(&mut self, command: &str) -> bool {
+        let (name, argument) = command
+            .split_once(char::is_whitespace)
+            .map_or((command, ""), |(name, argument)| (name, argument.trim()));
+
+        match name {
+            "tape" => {
+                println!("pointer = {}", self.vm.pointer());
+                println!("{}", tape_window(&self.vm, 8, self.format));
+            }
+            "reset" => self.vm.reset(),
+            "load" => self.load(argument),
+            "format" => match argument {
+                "dec" => self.format = CellFormat::Decimal,
+                "hex" => self.format = CellFormat::Hex,
+                "char" => self.format = CellFormat::Char,
+                _ => println!("error: expected one of dec, hex or char"),
+            },
+            "help" => println!("{HELP}"),
+            "quit" | "q" => return false,
+            _ => println!("error: unknown command `:{name}`, try `:help`"),
+        }
+
+        true
+    }
+
+    fn load(&mut self, path: &str) {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(error) => {
+                println!("error: failed to read {path}: {error}");
+                return;
+            }
+        };
+
+        match Parser::parse(Lexer::new(source)) {
+            Ok(ast) => self.execute(&bytecode::compile(&ast, &self.options)),
+            Err(error) => println!("error: {path}:{error}"),
+        }
+    }
+}
@@ -0,0 +1,329 @@
+use clap::ValueEnum;
+use std::{
+    ffi::OsStr,
+    fs,
+    io::{self, BufReader, BufWriter},
+    path::Path,
+    process,
+    time::Duration,
+};
+
+use crate::{
+    bytecode::{self, Limits, Observer, Program, Vm, VmError},
+    cli::{MachineArgs, RunArgs, TapeMode, UNBOUNDED_CELL_BITS},
+    codegen::CodegenOptions,
+    codegen_options,
+    coverage::Coverage,
+    parse_source,
+    profiler::Profiler,
+    snapshot::Snapshot,
+    tape_view::{tape_window, CellFormat},
+    trace::{parse_range, Tracer},
+    vm_config,
+};
+
+// Exit status when a resource limit is exceeded
+const EXIT_LIMIT_EXCEEDED: i32 = 3;
+
+// Also returns the source, unless the program was precompiled
+fn load_program(input_path: &str, options: &CodegenOptions) -> (Program, Option<String>) {
+    let path = Path::new(input_path);
+
+    let is_bytecode = path
+        .extension()
+        .is_some_and(|extension| extension.to_ascii_lowercase() == OsStr::new("bfc"));
+
+    if is_bytecode {
+        let bytes =
+            fs::read(path).unwrap_or_else(|_| panic!("Failed to read bytecode {input_path}"));
+
+        let program = Program::from_bytes(&bytes).unwrap_or_else(|error| {
+            eprintln!("{input_path}: {error}");
+            process::exit(1);
+        });
+
+        if program.cell_bits == UNBOUNDED_CELL_BITS && options.cell_bits != UNBOUNDED_CELL_BITS {
+            eprintln!(
+                "{input_path}: compiled for unbounded cells, run it with --cell-bits unbounded"
+            );
+            process::exit(1);
+        }
+
+        if program.cell_bits != options.cell_bits {
+            eprintln!(
+                "{input_path}: compiled for {}-bit cells, run it with --cell-bits {}",
+                program.cell_bits, program.cell_bits
+            );
+            process::exit(1);
+        }
+
+        if (program.cell_overflow, program.cell_signed)
+            != (options.cell_overflow, options.cell_signed)
+        {
+            let overflow = program.cell_overflow.to_possible_value().unwrap();
+            let signed = if program.cell_signed {
+                " --cell-signed"
+            } else {
+                ""
+            };
+
+            eprintln!(
+                "{input_path}: compiled with --cell-overflow {}{signed}, run it with the same options",
+                overflow.get_name()
+            );
+            process::exit(1);
+        }
+
+        return (program, None);
+    }
+
+    let source =
+        fs::read_to_string(path).unwrap_or_else(|_| panic!("Failed to read source {input_path}"));
+    let ast = parse_source(input_path, source.clone());
+
+    (bytecode::compile(&ast, options), Some(source))
+}
+
+pub fn run(args: &RunArgs) {
+    // Coverage needs every op to map back to the commands it came from
+    let optimized = args.optimizations && args.coverage.is_none();
+    let options = codegen_options(optimized, &args.machine);
+    check_snapshots(args);
+    let (program, source) = load_program(&args.input_path, &options);
+
+    let mut input = io::stdin().lock();
+    let mut output = BufWriter::new(io::stdout().lock());
+
+    let limits = Limits {
+        max_steps: args.max_steps,
+        max_output_bytes: args.max_output_bytes,
+        timeout: args.timeout.map(Duration::from_secs_f64),
+        max_tape: args.max_tape,
+    };
+
+    let mut profiler = (args.profile || args.annotate.is_some()).then(|| Profiler::new(&program));
+
+    let mut coverage = args.coverage.as_ref().map(|_| Coverage::new(&program));
+
+    let mut tracer = args.trace.as_ref().map(|path| {
+        let range = args.trace_range.as_ref().map(|range| {
+            parse_range(range).unwrap_or_else(|| {
+                eprintln!(
+                    "Invalid trace range `{range}`, expected something like 3:1-10:5 or 3-10"
+                );
+                process::exit(1);
+            })
+        });
+        let file = fs::File::create(path).unwrap_or_else(|_| panic!("Failed to create {path}"));
+
+        Tracer::new(
+            &program,
+            BufWriter::new(file),
+            args.trace_format,
+            args.trace_sample,
+            range,
+        )
+    });
+
+    let mut observers: Vec<&mut dyn Observer> = vec![];
+    if let Some(profiler) = &mut profiler {
+        observers.push(profiler);
+    }
+    if let Some(coverage) = &mut coverage {
+        observers.push(coverage);
+    }
+    if let Some(tracer) = &mut tracer {
+        observers.push(tracer);
+    }
+
+    let mut record = args
+        .record
+        .as_ref()
+        .map(|path| fs::File::create(path).unwrap_or_else(|_| panic!("Failed to create {path}")));
+    let mut replay = args.replay.as_ref().map(|path| {
+        BufReader::new(fs::File::open(path).unwrap_or_else(|_| panic!("Failed to open {path}")))
+    });
+
+    let mut vm = Vm::new(
+        &program,
+        vm_config(&args.machine),
+        limits,
+        &mut input,
+        &mut output,
+    );
+    if let Some(record) = &mut record {
+        vm.record_input(record);
+    }
+    if let Some(replay) = &mut replay {
+        vm.replay_input(replay);
+    }
+    if let Some(path) = &args.load_tape {
+        load_tape(&mut vm, path, &args.machine, args.max_tape);
+    }
+    let result = if observers.is_empty() {
+        vm.run()
+    } else {
+        vm.run_with(&mut observers)
+    };
+
+    if let Some(tracer) = &mut tracer {
+        tracer.finish(&vm);
+    }
+
+    if let Some(profiler) = &mut profiler {
+        profiler.finish(&vm);
+        eprint!("{}", profiler.report(source.as_deref(), args.profile_top));
+
+        if let Some(path) = &args.annotate {
+            match &source {
+                Some(source) => fs::write(path, profiler.annotate(source))
+                    .unwrap_or_else(|_| panic!("Failed to write {path}")),
+                None => eprintln!("Can't annotate precompiled bytecode, it has no source"),
+            }
+        }
+    }
+
+    if let (Some(coverage), Some(path)) = (&coverage, &args.coverage) {
+        match &source {
+            Some(source) => {
+                let source_path = fs::canonicalize(&args.input_path)
+                    .map_or(args.input_path.clone(), |path| path.display().to_string());
+
+                fs::write(path, coverage.lcov(&source_path, source))
+                    .unwrap_or_else(|_| panic!("Failed to write {path}"));
+            }
+            None => eprintln!("Can't write coverage for precompiled bytecode, it has no source"),
+        }
+    }
+
+    if let Err(error) = result {
+        let span = program.spans.get(vm.pc());
+
+        match (&error, span) {
+            // Same messages as compiled programs with --bounds-check and
+            // --cell-overflow trap
+            (VmError::PointerOutOfRange { pointer }, Some(span)) => eprintln!(
+                "pointer out of range at {}:{}, pointer = {pointer}",
+                args.input_path, span.start
+            ),
+            (VmError::CellOverflow { pointer }, Some(span)) => eprintln!(
+                "cell overflow at {}:{span}, pointer = {pointer}",
+                args.input_path
+            ),
+            (_, Some(span)) => {
+                eprintln!("{error}");
+                eprintln!("  at {}:{}", args.input_path, span.start);
+            }
+            (_, None) => eprintln!("{error}"),
+        }
+        eprintln!("  after {} steps", vm.steps());
+        print_tape(&vm);
+
+        match error {
+            VmError::LimitExceeded(_) => process::exit(EXIT_LIMIT_EXCEEDED),
+            _ => process::exit(1),
+        }
+    }
+
+    if let Some(path) = &args.save_tape {
+        save_tape(&vm, path, &args.machine);
+    }
+}
+
+fn check_snapshots(args: &RunArgs) {
+    let uses_snapshots = args.load_tape.is_some() || args.save_tape.is_some();
+
+    if uses_snapshots && args.machine.cell_bits == UNBOUNDED_CELL_BITS {
+        eprintln!("Tape snapshots don't support unbounded cells");
+        process::exit(1);
+    }
+}
+
+// Same checks as compiled programs, with the same messages
+fn load_tape(vm: &mut Vm, path: &str, machine: &MachineArgs, max_tape: Option<usize>) {
+    let bytes = fs::read(path).unwrap_or_else(|_| panic!("Failed to read {path}"));
+    let snapshot = Snapshot::from_bytes(&bytes).unwrap_or_else(|error| {
+        eprintln!("{path}: {error}");
+        process::exit(1);
+    });
+
+    let fail = |message: String| -> ! {
+        eprintln!("{path}: {message}");
+        process::exit(1);
+    };
+
+    let growable = machine.tape == TapeMode::Growable;
+    let size = snapshot.cells.len();
+
+    if snapshot.cell_bits != machine.cell_bits {
+        fail(format!(
+            "snapshot of {}-bit cells, run it with --cell-bits {}",
+            snapshot.cell_bits, snapshot.cell_bits
+        ));
+    }
+    if !growable && size != machine.tape_size {
+        fail(format!(
+            "snapshot of {size} cells, run it with --tape-size {size}"
+        ));
+    }
+    if snapshot.origin != origin(machine) {
+        fail(format!(
+            "snapshot with cell 0 at {}, run it with the same --tape",
+            snapshot.origin
+        ));
+    }
+
+    let index = snapshot.pointer + snapshot.origin as isize;
+    if index < 0 || (!growable && index as usize >= size) {
+        fail(format!("pointer {} is off the tape", snapshot.pointer));
+    }
+
+    // A growable tape grows to fit the snapshot and the pointer, the cells
+    // past a shorter snapshot stay 0. Cells past --max-tape are left out, it
+    // keeps the ones around cell 0.
+    let mut cells = snapshot.cells[origin(machine) - vm_origin(vm)..].to_vec();
+    if growable {
+        cells.resize(size.max(index as usize + 1).max(vm.tape().len()), 0);
+    }
+    if let Some(max) = max_tape {
+        cells.truncate(max);
+    }
+
+    vm.set_tape(&cells);
+    vm.restore(0, snapshot.pointer, 0);
+}
+
+fn save_tape(vm: &Vm, path: &str, machine: &MachineArgs) {
+    // The tape is shorter with --max-tape, but snapshots have the full size
+    let mut cells = vec![0; origin(machine) - vm_origin(vm)];
+    cells.extend(vm.tape());
+    cells.resize(cells.len().max(machine.tape_size), 0);
+
+    let snapshot = Snapshot {
+        cell_bits: machine.cell_bits,
+        origin: origin(machine),
+        pointer: vm.pointer(),
+        cells,
+    };
+
+    fs::write(path, snapshot.to_bytes()).unwrap_or_else(|_| panic!("Failed to write {path}"));
+}
+
+// Where cell 0 is on the tape of the program, the middle of a bidirectional
+// tape
+fn origin(machine: &MachineArgs) -> usize {
+    match machine.tape {
+        TapeMode::Bidirectional => machine.tape_size / 2,
+        _ => 0,
+    }
+}
+
+// Where cell 0 is on the tape of the VM, which --max-tape makes shorter
+fn vm_origin(vm: &Vm) -> usize {
+    -vm.first_cell() as usize
+}
+
+fn print_tape(vm: &Vm) {
+    eprintln!("  pointer = {}", vm.pointer());
+    eprintln!("  {}", tape_window(vm, 8, CellFormat::Decimal));
+}
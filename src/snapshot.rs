@@ -0,0 +1,233 @@
+// Tape snapshots for --load-tape and --save-tape, the same format compiled
+// programs use: a magic number, version byte and the cell width in bits, then
+// the number of cells, where cell 0 is on the tape and the pointer relative to
+// it as 64-bit integers, followed by the cells. Everything is little endian
+// and every cell takes as many bytes as its width.
+
+use std::fmt;
+
+use crate::bytecode::Cell;
+
+const MAGIC: &[u8; 4] = b"BFT\0";
+const VERSION: u8 = 1;
+
+pub struct Snapshot {
+    pub cell_bits: u32,
+    pub origin: usize,
+    pub pointer: isize,
+    pub cells: Vec<Cell>,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnsupportedCellBits(u8),
+    UnexpectedEnd,
+    TrailingData,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::BadMagic => write!(f, "not a tape snapshot"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {version}")
+            }
+            SnapshotError::UnsupportedCellBits(bits) => write!(f, "unsupported cell width {bits}"),
+            SnapshotError::UnexpectedEnd => write!(f, "unexpected end of snapshot"),
+            SnapshotError::TrailingData => write!(f, "unexpected data after the cells"),
+        }
+    }
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let cell_bytes = self.cell_bits as usize / 8;
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        bytes.push(self.cell_bits as u8);
+        bytes.extend((self.cells.len() as u64).to_le_bytes());
+        bytes.extend((self.origin as u64).to_le_bytes());
+        bytes.extend((self.pointer as i64).to_le_bytes());
+
+        for cell in &self.cells {
+            bytes.extend(&cell.to_le_bytes()[..cell_bytes]);
+        }
+
+        bytes
+    }
+
+    // Only checks the format, whether the snapshot fits the tape is up to
+    // the caller
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let mut reader = Reader { bytes, i: 0 };
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+
+        let version = reader.take(1)?[0];
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let cell_bits = reader.take(1)?[0];
+        if ![8, 16, 32, 64].contains(&cell_bits) {
+            return Err(SnapshotError::UnsupportedCellBits(cell_bits));
+        }
+
+        let size = reader.u64()?;
+        let origin = reader.u64()?;
+        let pointer = reader.u64()? as i64;
+
+        let cell_bytes = cell_bits as usize / 8;
+        let cells = usize::try_from(size)
+            .ok()
+            .and_then(|size| size.checked_mul(cell_bytes))
+            .ok_or(SnapshotError::UnexpectedEnd)?;
+        let cells = reader
+            .take(cells)?
+            .chunks(cell_bytes)
+            .map(|cell| {
+                let mut bytes = [0; 8];
+                bytes[..cell_bytes].copy_from_slice(cell);
+                Cell::from_le_bytes(bytes)
+            })
+            .collect();
+
+        if reader.i != bytes.len() {
+            return Err(SnapshotError::TrailingData);
+        }
+
+        Ok(Snapshot {
+            cell_bits: cell_bits as u32,
+            origin: origin as usize,
+            pointer: pointer as isize,
+            cells,
+        })
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    i: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, amount: usize) -> Result<&'a [u8], SnapshotError> {
+        let slice = self
+            .bytes
+            .get(self.i..self.i.saturating_add(amount))
+            .ok_or(SnapshotError::UnexpectedEnd)?;
+        self.i += amount;
+        Ok(slice)
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(cell_bits: u32) -> Snapshot {
+        let max = Cell::MAX >> (64 - cell_bits);
+
+        Snapshot {
+            cell_bits,
+            origin: 2,
+            pointer: -1,
+            cells: vec![0, 1, max, 0, max - 1],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for cell_bits in [8, 16, 32, 64] {
+            let snapshot = snapshot(cell_bits);
+            let read = Snapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+
+            assert_eq!(read.cell_bits, cell_bits);
+            assert_eq!(read.origin, 2);
+            assert_eq!(read.pointer, -1);
+            assert_eq!(read.cells, snapshot.cells);
+        }
+    }
+
+    // Compiled programs read and write the same bytes
+    #[test]
+    fn layout() {
+        let bytes = snapshot(16).to_bytes();
+
+        assert_eq!(&bytes[..6], b"BFT\0\x01\x10");
+        assert_eq!(bytes[6..14], 5u64.to_le_bytes());
+        assert_eq!(bytes[14..22], 2u64.to_le_bytes());
+        assert_eq!(bytes[22..30], (-1i64).to_le_bytes());
+        assert_eq!(bytes[30..], [0, 0, 1, 0, 0xff, 0xff, 0, 0, 0xfe, 0xff]);
+    }
+
+    #[test]
+    fn bad_header() {
+        let mut bytes = snapshot(8).to_bytes();
+        bytes[1] = b'C';
+        assert!(matches!(
+            Snapshot::from_bytes(&bytes),
+            Err(SnapshotError::BadMagic)
+        ));
+
+        let mut bytes = snapshot(8).to_bytes();
+        bytes[4] = VERSION + 1;
+        assert!(matches!(
+            Snapshot::from_bytes(&bytes),
+            Err(SnapshotError::UnsupportedVersion(version)) if version == VERSION + 1
+        ));
+
+        let mut bytes = snapshot(8).to_bytes();
+        bytes[5] = 24;
+        assert!(matches!(
+            Snapshot::from_bytes(&bytes),
+            Err(SnapshotError::UnsupportedCellBits(24))
+        ));
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = snapshot(32).to_bytes();
+
+        for length in 0..bytes.len() {
+            assert!(
+                matches!(
+                    Snapshot::from_bytes(&bytes[..length]),
+                    Err(SnapshotError::UnexpectedEnd)
+                ),
+                "{length} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn trailing_data() {
+        let mut bytes = snapshot(8).to_bytes();
+        bytes.push(0);
+
+        assert!(matches!(
+            Snapshot::from_bytes(&bytes),
+            Err(SnapshotError::TrailingData)
+        ));
+    }
+
+    #[test]
+    fn size_too_large() {
+        let mut bytes = snapshot(64).to_bytes();
+        bytes[6..14].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        assert!(matches!(
+            Snapshot::from_bytes(&bytes),
+            Err(SnapshotError::UnexpectedEnd)
+        ));
+    }
+}
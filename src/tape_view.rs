@@ -0,0 +1,63 @@
+use crate::bytecode::{cell_value, BigInt, Cell, Vm};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellFormat {
+    Decimal,
+    Hex,
+    Char,
+}
+
+impl CellFormat {
+    // `signed` is the width of cells if they are signed, hex and characters
+    // always show the bits
+    pub fn format(&self, value: Cell, signed: Option<u32>) -> String {
+        match self {
+            CellFormat::Decimal => match signed {
+                Some(bits) => cell_value(value, bits, true).to_string(),
+                None => value.to_string(),
+            },
+            CellFormat::Hex => format!("{value:02x}"),
+            CellFormat::Char => match value {
+                0x20..=0x7e => format!("'{}'", value as u8 as char),
+                _ => format!("{value}"),
+            },
+        }
+    }
+
+    pub fn format_unbounded(&self, value: &BigInt) -> String {
+        match (self, value.to_i64()) {
+            (CellFormat::Hex, _) => format!("{value:x}"),
+            (CellFormat::Char, Some(character @ 0x20..=0x7e)) => {
+                format!("'{}'", character as u8 as char)
+            }
+            _ => value.to_string(),
+        }
+    }
+}
+
+// The cells within `radius` of the pointer, with the current cell in brackets.
+// Indices are signed because cell 0 is in the middle of a bidirectional tape.
+pub fn tape_window(vm: &Vm, radius: isize, format: CellFormat) -> String {
+    let (first, pointer) = (vm.first_cell(), vm.pointer());
+    let last = first + vm.tape_len() as isize;
+    let start = (pointer - radius).clamp(first, last);
+    let end = (pointer + radius + 1).clamp(first, last);
+
+    let cells = (start..end)
+        .map(|index| {
+            let value = match vm.unbounded_cell(index) {
+                Some(value) => format.format_unbounded(&value),
+                None => format.format(vm.tape()[(index - first) as usize], vm.signed_bits()),
+            };
+
+            if index == pointer {
+                format!("[{value}]")
+            } else {
+                value
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    format!("tape[{start}..{end}] = {cells}")
+}
@@ -1,4 +1,5 @@
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Token {
     Right,
     Left,
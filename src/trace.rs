@@ -0,0 +1,336 @@
+use std::io::Write;
+
+use crate::{
+    bytecode::{Cell, Observer, Op, Program, Vm},
+    cli::{CellOverflow, TraceFormat, UNBOUNDED_CELL_BITS},
+    span::{Position, Span},
+};
+
+// Parses `line:column-line:column` or `line-line`
+pub fn parse_range(range: &str) -> Option<Span> {
+    let (start, end) = range.split_once('-')?;
+
+    let position = |text: &str, column: usize| match text.split_once(':') {
+        Some((line, column)) => Some(Position::new(line.parse().ok()?, column.parse().ok()?)),
+        None => Some(Position::new(text.parse().ok()?, column)),
+    };
+
+    Some(Span::new(position(start, 1)?, position(end, usize::MAX)?))
+}
+
+// The op that is being traced: its step, the pointer, the cell it works on
+// and the value of that cell before it ran
+struct Pending {
+    step: u64,
+    pointer: isize,
+    cell: isize,
+    before: Option<String>,
+}
+
+pub struct Tracer<'p, W: Write> {
+    program: &'p Program,
+    output: W,
+    format: TraceFormat,
+    // Only every `sample`th step is written
+    sample: u64,
+    range: Option<Span>,
+    pending: Option<Pending>,
+    // Loops that are running, for the Chrome format
+    loops: Vec<usize>,
+    events: usize,
+}
+
+impl<W: Write> Observer for Tracer<'_, W> {
+    fn before(&mut self, vm: &Vm, pc: usize) {
+        if self.format != TraceFormat::Jsonl
+            || !vm.steps().is_multiple_of(self.sample)
+            || !self.in_range(pc)
+        {
+            return;
+        }
+
+        let offset = match self.program.ops[pc] {
+            Op::Add { offset, .. }
+            | Op::Set { offset, .. }
+            | Op::MulAdd { offset, .. }
+            | Op::Output { offset }
+            | Op::Input { offset } => offset as isize,
+            _ => 0,
+        };
+        let cell = vm.pointer() + offset;
+
+        self.pending = Some(Pending {
+            step: vm.steps(),
+            pointer: vm.pointer(),
+            cell,
+            before: cell_value(vm, cell),
+        });
+    }
+
+    fn after(&mut self, vm: &Vm, pc: usize) {
+        match self.format {
+            TraceFormat::Jsonl => {
+                if let Some(pending) = self.pending.take() {
+                    self.write_step(vm, pc, pending);
+                }
+            }
+            TraceFormat::Chrome => match self.program.ops[pc] {
+                Op::JumpIfZero(_) if vm.pc() == pc + 1 => {
+                    self.loops.push(pc);
+                    if self.in_range(pc) {
+                        self.write_event(pc, "B", vm.steps() - 1);
+                    }
+                }
+                Op::JumpIfNonZero(target) if vm.pc() != target as usize => {
+                    if let Some(start) = self.loops.pop() {
+                        if self.in_range(start) {
+                            self.write_event(start, "E", vm.steps());
+                        }
+                    }
+                }
+                _ => {}
+            },
+        }
+    }
+}
+
+impl<'p, W: Write> Tracer<'p, W> {
+    pub fn new(
+        program: &'p Program,
+        mut output: W,
+        format: TraceFormat,
+        sample: u64,
+        range: Option<Span>,
+    ) -> Self {
+        if format == TraceFormat::Chrome {
+            write!(output, "{{\"traceEvents\":[").expect("Failed to write trace");
+        }
+
+        Self {
+            program,
+            output,
+            format,
+            sample: sample.max(1),
+            range,
+            pending: None,
+            loops: vec![],
+            events: 0,
+        }
+    }
+
+    fn in_range(&self, pc: usize) -> bool {
+        let span = self.program.spans[pc];
+
+        self.range
+            .is_none_or(|range| span.start <= range.end && range.start <= span.end)
+    }
+
+    fn write_step(&mut self, vm: &Vm, pc: usize, pending: Pending) {
+        let span = self.program.spans[pc];
+        let value = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+
+        writeln!(
+            self.output,
+            "{{\"step\":{},\"span\":{},{},\"pointer\":{},\"cell\":{},\"before\":{},\"after\":{}}}",
+            pending.step,
+            span_json(span),
+            self.op_json(self.program.ops[pc]),
+            pending.pointer,
+            pending.cell,
+            value(pending.before),
+            value(cell_value(vm, pending.cell)),
+        )
+        .expect("Failed to write trace");
+    }
+
+    // The name of the op and its operands. Values are written like cells,
+    // amounts that don't wrap and values of unbounded cells are signed.
+    fn op_json(&self, op: Op) -> String {
+        let program = self.program;
+        let unbounded = program.cell_bits == UNBOUNDED_CELL_BITS;
+        let value = |value: Cell, signed: bool| {
+            if signed {
+                (value as i64).to_string()
+            } else {
+                value.to_string()
+            }
+        };
+        let amount = |amount| {
+            value(
+                amount,
+                unbounded || program.cell_overflow != CellOverflow::Wrap,
+            )
+        };
+
+        match op {
+            Op::Add {
+                offset,
+                value: added,
+            } => {
+                format!(
+                    "\"op\":\"add\",\"offset\":{offset},\"value\":{}",
+                    amount(added)
+                )
+            }
+            Op::Set { offset, value: set } => {
+                format!(
+                    "\"op\":\"set\",\"offset\":{offset},\"value\":{}",
+                    value(set, unbounded)
+                )
+            }
+            Op::Move(amount) => format!("\"op\":\"move\",\"amount\":{amount}"),
+            Op::MulAdd { offset, factor } => format!(
+                "\"op\":\"mul_add\",\"offset\":{offset},\"factor\":{}",
+                value(factor, unbounded)
+            ),
+            Op::Scan(step) => format!("\"op\":\"scan\",\"step\":{step}"),
+            Op::Output { offset } => format!("\"op\":\"output\",\"offset\":{offset}"),
+            Op::Input { offset } => format!("\"op\":\"input\",\"offset\":{offset}"),
+            Op::JumpIfZero(target) => format!("\"op\":\"jump_if_zero\",\"target\":{target}"),
+            Op::JumpIfNonZero(target) => {
+                format!("\"op\":\"jump_if_non_zero\",\"target\":{target}")
+            }
+            Op::JumpIfNegative(target) => {
+                format!("\"op\":\"jump_if_negative\",\"target\":{target}")
+            }
+        }
+    }
+
+    // Steps are used as the timestamps, so one step shows up as a microsecond
+    fn write_event(&mut self, start: usize, phase: &str, step: u64) {
+        let span = self.program.spans[start];
+        let separator = if self.events == 0 { "" } else { "," };
+        self.events += 1;
+
+        write!(
+            self.output,
+            "{separator}\n{{\"name\":\"loop {}\",\"cat\":\"loop\",\"ph\":\"{phase}\",\"ts\":{step},\"pid\":1,\"tid\":1,\"args\":{{\"span\":{}}}}}",
+            span.start,
+            span_json(span),
+        )
+        .expect("Failed to write trace");
+    }
+
+    // Closes loops that were still running and finishes the file
+    pub fn finish(&mut self, vm: &Vm) {
+        if self.format == TraceFormat::Chrome {
+            while let Some(start) = self.loops.pop() {
+                if self.in_range(start) {
+                    self.write_event(start, "E", vm.steps());
+                }
+            }
+
+            writeln!(self.output, "\n]}}").expect("Failed to write trace");
+        }
+
+        self.output.flush().expect("Failed to write trace");
+    }
+}
+
+// As a JSON number, unbounded cells can be larger than any integer type
+fn cell_value(vm: &Vm, cell: isize) -> Option<String> {
+    match vm.unbounded_cell(cell) {
+        Some(value) => Some(value.to_string()),
+        None => vm.cell_at(cell).map(|value| value.to_string()),
+    }
+}
+
+fn span_json(span: Span) -> String {
+    format!(
+        "{{\"start\":{{\"line\":{},\"column\":{}}},\"end\":{{\"line\":{},\"column\":{}}}}}",
+        span.start.line, span.start.column, span.end.line, span.end.column
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::{compile_source, Limits};
+
+    fn trace(source: &str, format: TraceFormat, sample: u64, range: Option<&str>) -> String {
+        let (program, config) = compile_source(source, &[]);
+        let mut input: &[u8] = b"";
+        let mut output = vec![];
+        let mut trace = vec![];
+
+        let range = range.map(|range| parse_range(range).unwrap());
+        let mut tracer = Tracer::new(&program, &mut trace, format, sample, range);
+        let mut vm = Vm::new(&program, config, Limits::default(), &mut input, &mut output);
+        vm.run_with(&mut [&mut tracer]).unwrap();
+        tracer.finish(&vm);
+        drop(tracer);
+
+        String::from_utf8(trace).unwrap()
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(
+            parse_range("3:1-10:5"),
+            Some(Span::new(Position::new(3, 1), Position::new(10, 5)))
+        );
+        assert_eq!(
+            parse_range("3-10"),
+            Some(Span::new(
+                Position::new(3, 1),
+                Position::new(10, usize::MAX)
+            ))
+        );
+        assert_eq!(parse_range("3"), None);
+        assert_eq!(parse_range("3:a-4"), None);
+    }
+
+    #[test]
+    fn jsonl() {
+        let trace = trace("+>-\n.", TraceFormat::Jsonl, 1, None);
+        let lines: Vec<&str> = trace.lines().collect();
+
+        assert_eq!(
+            lines,
+            [
+                "{\"step\":0,\"span\":{\"start\":{\"line\":1,\"column\":1},\"end\":{\"line\":1,\"column\":1}},\
+                 \"op\":\"add\",\"offset\":0,\"value\":1,\"pointer\":0,\"cell\":0,\"before\":0,\"after\":1}",
+                "{\"step\":1,\"span\":{\"start\":{\"line\":1,\"column\":2},\"end\":{\"line\":1,\"column\":2}},\
+                 \"op\":\"move\",\"amount\":1,\"pointer\":0,\"cell\":0,\"before\":1,\"after\":1}",
+                "{\"step\":2,\"span\":{\"start\":{\"line\":1,\"column\":3},\"end\":{\"line\":1,\"column\":3}},\
+                 \"op\":\"add\",\"offset\":0,\"value\":255,\"pointer\":1,\"cell\":1,\"before\":0,\"after\":255}",
+                "{\"step\":3,\"span\":{\"start\":{\"line\":2,\"column\":1},\"end\":{\"line\":2,\"column\":1}},\
+                 \"op\":\"output\",\"offset\":0,\"pointer\":1,\"cell\":1,\"before\":255,\"after\":255}",
+            ]
+        );
+    }
+
+    #[test]
+    fn sample_and_range() {
+        let steps = |trace: String| -> Vec<String> {
+            trace
+                .lines()
+                .map(|line| line.split(',').next().unwrap().to_string())
+                .collect()
+        };
+
+        assert_eq!(
+            steps(trace("+>+>+>+", TraceFormat::Jsonl, 3, None)),
+            ["{\"step\":0", "{\"step\":3", "{\"step\":6"]
+        );
+        assert_eq!(
+            steps(trace("+>+\n>+>+", TraceFormat::Jsonl, 1, Some("2-2"))),
+            ["{\"step\":3", "{\"step\":4", "{\"step\":5", "{\"step\":6"]
+        );
+    }
+
+    #[test]
+    fn chrome() {
+        let trace = trace("+[-]", TraceFormat::Chrome, 1, None);
+
+        assert_eq!(
+            trace,
+            "{\"traceEvents\":[\n\
+             {\"name\":\"loop 1:2\",\"cat\":\"loop\",\"ph\":\"B\",\"ts\":1,\"pid\":1,\"tid\":1,\
+             \"args\":{\"span\":{\"start\":{\"line\":1,\"column\":2},\"end\":{\"line\":1,\"column\":2}}}},\n\
+             {\"name\":\"loop 1:2\",\"cat\":\"loop\",\"ph\":\"E\",\"ts\":4,\"pid\":1,\"tid\":1,\
+             \"args\":{\"span\":{\"start\":{\"line\":1,\"column\":2},\"end\":{\"line\":1,\"column\":2}}}}\n\
+             ]}\n"
+        );
+    }
+}